@@ -15,6 +15,7 @@
 //! comes from some server or from a blockchain.
 //!
 
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::{Read, Write};
 
@@ -26,28 +27,136 @@ use ser;
 const TAG_SIZE: usize = 8;
 /// Maximum length of a URI in a "pending" attestation
 const MAX_URI_LEN: usize = 1000;
+/// Default maximum length of the payload of any attestation; see
+/// `ser::ParseOptions::max_attestation_len`
+pub const MAX_ATTESTATION_LEN: usize = 8192;
 
 /// Tag indicating a Bitcoin attestation
 const BITCOIN_TAG: &[u8] = b"\x05\x88\x96\x0d\x73\xd7\x19\x01";
+/// Tag indicating a Litecoin attestation
+const LITECOIN_TAG: &[u8] = b"\x06\x86\x9a\x0d\x73\xd7\x1b\x45";
 /// Tag indicating a pending attestation
 const PENDING_TAG: &[u8] = b"\x83\xdf\xe3\x0d\x2e\xf9\x0c\x8e";
 
+/// A blockchain that attestations may be anchored in
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum Chain {
+    /// The Bitcoin blockchain
+    Bitcoin,
+    /// The Litecoin blockchain
+    Litecoin
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chain::Bitcoin => f.write_str("Bitcoin"),
+            Chain::Litecoin => f.write_str("Litecoin"),
+        }
+    }
+}
+
+impl Chain {
+    /// Every chain we know the attestation tag of
+    pub const ALL: [Chain; 2] = [Chain::Bitcoin, Chain::Litecoin];
+
+    /// The tag identifying attestations from this chain
+    pub fn tag(self) -> &'static [u8] {
+        match self {
+            Chain::Bitcoin => BITCOIN_TAG,
+            Chain::Litecoin => LITECOIN_TAG
+        }
+    }
+
+    /// The chain whose attestations carry the given tag, if any
+    pub fn from_tag(tag: &[u8]) -> Option<Chain> {
+        Chain::ALL.iter().cloned().find(|chain| chain.tag() == tag)
+    }
+}
+
+/// The height of a block in a blockchain, the number of blocks before it.
+///
+/// Unlike block hashes and Merkle roots, which are byte strings that block
+/// explorers conventionally display reversed, a height is a plain number
+/// with no byte order to get wrong: in a timestamp it is written as a
+/// little-endian base-128 varint, like every other number in the format,
+/// and parsed straight into this type.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct BlockHeight(pub u32);
+
+impl fmt::Display for BlockHeight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl From<u32> for BlockHeight {
+    fn from(height: u32) -> BlockHeight {
+        BlockHeight(height)
+    }
+}
+
+impl From<BlockHeight> for u32 {
+    fn from(height: BlockHeight) -> u32 {
+        height.0
+    }
+}
+
+impl From<BlockHeight> for usize {
+    fn from(height: BlockHeight) -> usize {
+        height.0 as usize
+    }
+}
+
+impl TryFrom<usize> for BlockHeight {
+    type Error = Error;
+
+    fn try_from(height: usize) -> Result<BlockHeight, Error> {
+        u32::try_from(height)
+            .map(BlockHeight)
+            .map_err(|_| Error::BadBlockHeight(height))
+    }
+}
+
+/// A source of Bitcoin block headers, such as a local node or a block
+/// explorer, for verifying Bitcoin attestations
+pub trait BlockHeaderProvider {
+    /// The Merkle root of the block at `height`, in the byte order it
+    /// appears in the block header. Block explorers usually display roots
+    /// reversed, so implementations for them need to reverse them back.
+    fn merkle_root(&self, height: BlockHeight) -> Result<[u8; 32], Error>;
+
+    /// The time in the header of the block at `height`, in seconds since
+    /// the Unix epoch
+    fn block_time(&self, height: BlockHeight) -> Result<u32, Error>;
+}
+
+/// A source of full Bitcoin blocks, for checking that a timestamp's
+/// transaction really is in the block its attestation names
+pub trait BlockProvider {
+    /// The transactions of the block at `height`, in block order, each
+    /// serialized without witness data (the form their txids hash)
+    fn transactions(&self, height: BlockHeight) -> Result<Vec<Vec<u8>>, Error>;
+}
+
 /// An attestation that some data existed at some time
 #[allow(missing_docs)]
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Attestation {
-    /// An attestation from a Bitcoin blockheader. This consists of a blockheight
-    /// and nothing more, it is expected that the current hash is equal to the
-    /// Merkle root of the block at this height.
-    Bitcoin {
-        height: usize
+    /// An attestation from a blockheader. This consists of a chain and a
+    /// blockheight and nothing more, it is expected that the current hash is
+    /// equal to the Merkle root of the block at this height.
+    Blockchain {
+        chain: Chain,
+        height: BlockHeight
     },
     /// An attestation from some server. It is commented at length in Peter Todd's
     /// `python-opentimestamps` that the server should be expected to keep anything
     /// it attests to, forever, and therefore the only thing we store locally is a
     /// single simple URI with a very restricted charset. (The restricted charset
     /// seems mainly to be to avoid the software being used for nefarious purposes,
-    /// as it will fetch this URI under some circumstances.)
+    /// as it will fetch this URI under some circumstances.) Build these with
+    /// `Attestation::pending`, which checks the URI.
     Pending {
         uri: String
     },
@@ -59,30 +168,45 @@ pub enum Attestation {
 }
 
 impl Attestation {
+    /// An attestation from the Bitcoin block at `height`
+    pub fn bitcoin(height: u32) -> Attestation {
+        Attestation::Blockchain { chain: Chain::Bitcoin, height: BlockHeight(height) }
+    }
+
+    /// An attestation from the Litecoin block at `height`
+    pub fn litecoin(height: u32) -> Attestation {
+        Attestation::Blockchain { chain: Chain::Litecoin, height: BlockHeight(height) }
+    }
+
+    /// A pending attestation from the calendar at `uri`, checking that the
+    /// URI is acceptable (see `validate_uri`). Building `Attestation::Pending`
+    /// directly skips the check, and a bad URI then only shows up when the
+    /// timestamp is parsed again.
+    pub fn pending(uri: String) -> Result<Attestation, Error> {
+        validate_uri(&uri)?;
+        Ok(Attestation::Pending { uri })
+    }
+
     /// Deserialize an arbitrary attestation
     pub fn deserialize<R: Read>(deser: &mut ser::Deserializer<R>) -> Result<Attestation, Error> {
+        deser.count_attestation()?;
         let tag = deser.read_fixed_bytes(TAG_SIZE)?;
         let len = deser.read_uint()?;
+        let max_len = deser.options().max_attestation_len;
+        if len > max_len {
+            return Err(Error::BadLength { min: 0, max: max_len, val: len });
+        }
 
-        if tag == BITCOIN_TAG {
-            let height = deser.read_uint()?;
-            Ok(Attestation::Bitcoin {
+        if let Some(chain) = Chain::from_tag(&tag) {
+            let height = BlockHeight::try_from(deser.read_uint()?)?;
+            Ok(Attestation::Blockchain {
+                chain,
                 height
             })
         } else if tag == PENDING_TAG {
-            // This validation logic copied from python-opentimestamps. Peter comments
-            // that he is deliberately avoiding ?, &, @, etc., to "keep us out of trouble"
             let uri_bytes = deser.read_bytes(0, MAX_URI_LEN)?;
             let uri_string = String::from_utf8(uri_bytes)?;
-            for ch in uri_string.chars() {
-                match ch {
-                    'a'..='z' => {}
-                    'A'..='Z' => {}
-                    '0'..='9' => {}
-                    '.' | '-' | '_' | '/' | ':' => {},
-                    x => return Err(Error::InvalidUriChar(x))
-                }
-            }
+            validate_uri(&uri_string)?;
             Ok(Attestation::Pending {
                 uri: uri_string
             })
@@ -98,9 +222,9 @@ impl Attestation {
     pub fn serialize<W: Write>(&self, ser: &mut ser::Serializer<W>) -> Result<(), Error> {
         let mut byte_ser = ser::Serializer::new(vec![]);
         match *self {
-            Attestation::Bitcoin { height } => {
-                ser.write_fixed_bytes(BITCOIN_TAG)?;
-                byte_ser.write_uint(height)?;
+            Attestation::Blockchain { chain, height } => {
+                ser.write_fixed_bytes(chain.tag())?;
+                byte_ser.write_uint(height.into())?;
                 ser.write_bytes(&byte_ser.into_inner())
             }
             Attestation::Pending { ref uri } => {
@@ -114,15 +238,204 @@ impl Attestation {
             }
         }
     }
+
+    /// The serialization of the attestation, as it appears in a timestamp
+    /// after the 0x00 attestation marker
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut ser = ser::Serializer::new(vec![]);
+        self.serialize(&mut ser)?;
+        Ok(ser.into_inner())
+    }
+
+    /// The number of bytes the attestation serializes to
+    pub fn serialized_len(&self) -> usize {
+        let payload_len = match *self {
+            Attestation::Blockchain { height, .. } => ser::uint_len(height.into()),
+            Attestation::Pending { ref uri } => ser::uint_len(uri.len()) + uri.len(),
+            Attestation::Unknown { ref data, .. } => data.len()
+        };
+        let tag_len = match *self {
+            Attestation::Unknown { ref tag, .. } => tag.len(),
+            _ => TAG_SIZE
+        };
+        tag_len + ser::uint_len(payload_len) + payload_len
+    }
+
+    /// If this attestation is anchored in a blockchain, the chain and the
+    /// height of the attesting block
+    pub fn anchor(&self) -> Option<(Chain, BlockHeight)> {
+        match *self {
+            Attestation::Blockchain { chain, height } => Some((chain, height)),
+            Attestation::Pending { .. } | Attestation::Unknown { .. } => None
+        }
+    }
+}
+
+/// Checks that `uri` is acceptable in a pending attestation: no longer than
+/// `MAX_URI_LEN`, and made only of letters, digits and `.-_/:`
+pub fn validate_uri(uri: &str) -> Result<(), Error> {
+    if uri.len() > MAX_URI_LEN {
+        return Err(Error::BadLength { min: 0, max: MAX_URI_LEN, val: uri.len() });
+    }
+    // This validation logic copied from python-opentimestamps. Peter comments
+    // that he is deliberately avoiding ?, &, @, etc., to "keep us out of trouble"
+    for ch in uri.chars() {
+        match ch {
+            'a'..='z' => {}
+            'A'..='Z' => {}
+            '0'..='9' => {}
+            '.' | '-' | '_' | '/' | ':' => {},
+            x => return Err(Error::InvalidUriChar(x))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Attestation {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Attestation> {
+        const URI_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.-_/:";
+
+        Ok(match u.int_in_range(0..=2)? {
+            0 => Attestation::Blockchain {
+                chain: *u.choose(&Chain::ALL)?,
+                height: BlockHeight(u.arbitrary()?)
+            },
+            1 => {
+                let len = u.int_in_range(0..=MAX_URI_LEN)?;
+                let mut uri = String::with_capacity(len);
+                for _ in 0..len {
+                    uri.push(*u.choose(URI_CHARS)? as char);
+                }
+                Attestation::Pending { uri }
+            }
+            _ => {
+                let mut tag = u.arbitrary::<[u8; TAG_SIZE]>()?.to_vec();
+                // A known tag would be parsed back as something other than Unknown
+                if Chain::from_tag(&tag).is_some() || tag == PENDING_TAG {
+                    tag[0] ^= 0xff;
+                }
+                let len = u.int_in_range(0..=MAX_ATTESTATION_LEN)?;
+                let data = u.bytes(len.min(u.len()))?.to_vec();
+                Attestation::Unknown { tag, data }
+            }
+        })
+    }
 }
 
 impl fmt::Display for Attestation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Attestation::Bitcoin { height } => write!(f, "Bitcoin block {}", height),
+            Attestation::Blockchain { chain, height } => write!(f, "{} block {}", chain, height),
             Attestation::Pending { ref uri } => write!(f, "Pending: update URI {}", uri),
             Attestation::Unknown { ref tag, ref data } => write!(f, "unknown attestation type {}: {}", Hexed(tag), Hexed(data)),
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blockchain_round_trip() {
+        for &chain in &Chain::ALL {
+            let attest = Attestation::Blockchain { chain, height: BlockHeight(449397) };
+            let mut ser = ser::Serializer::new(vec![]);
+            attest.serialize(&mut ser).unwrap();
+            let bytes = ser.into_inner();
+            assert_eq!(&bytes[..TAG_SIZE], chain.tag());
+            assert_eq!(Chain::from_tag(chain.tag()), Some(chain));
+
+            let mut deser = ser::Deserializer::new(&bytes[..]);
+            assert_eq!(Attestation::deserialize(&mut deser).unwrap(), attest);
+            assert_eq!(attest.anchor(), Some((chain, BlockHeight(449397))));
+        }
+        assert_eq!(Attestation::bitcoin(1), Attestation::Blockchain { chain: Chain::Bitcoin, height: BlockHeight(1) });
+        assert_eq!(Attestation::litecoin(1), Attestation::Blockchain { chain: Chain::Litecoin, height: BlockHeight(1) });
+        assert_eq!(Chain::from_tag(PENDING_TAG), None);
+
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        assert!(ots.timestamp.anchors().contains(&(Chain::Bitcoin, BlockHeight(449397))));
+    }
+
+    #[test]
+    fn block_height() {
+        let height = BlockHeight::from(449397);
+        assert_eq!(u32::from(height), 449397);
+        assert_eq!(usize::from(height), 449397);
+        assert_eq!(BlockHeight::try_from(usize::from(height)).unwrap(), height);
+        assert_eq!(height.to_string(), "449397");
+
+        // Through the wire format and back
+        let bytes = Attestation::Blockchain { chain: Chain::Bitcoin, height }.to_bytes().unwrap();
+        assert_matches!(Attestation::deserialize(&mut ser::Deserializer::new(&bytes[..])).unwrap(), Attestation::Blockchain { height: parsed, .. } if parsed == height);
+
+        // Heights too large for a u32 are refused when parsing
+        let mut payload = ser::Serializer::new(vec![]);
+        payload.write_uint(0x1_0000_0000).unwrap();
+        let mut ser = ser::Serializer::new(vec![]);
+        ser.write_fixed_bytes(BITCOIN_TAG).unwrap();
+        ser.write_bytes(&payload.into_inner()).unwrap();
+        let bytes = ser.into_inner();
+        assert_matches!(Attestation::deserialize(&mut ser::Deserializer::new(&bytes[..])), Err(Error::BadBlockHeight(0x1_0000_0000)));
+    }
+
+    #[test]
+    fn pending() {
+        let uri = "https://alice.btc.calendar.opentimestamps.org";
+        assert_eq!(Attestation::pending(uri.to_owned()).unwrap(), Attestation::Pending { uri: uri.to_owned() });
+        assert!(Attestation::pending(String::new()).is_ok());
+
+        for &(bad, ch) in &[("https://calendar.example.com/?x=1", '?'), ("https://user@example.com", '@'), ("https://caf\u{e9}.example", '\u{e9}')] {
+            assert_matches!(Attestation::pending(bad.to_owned()), Err(Error::InvalidUriChar(c)) if c == ch);
+        }
+        assert_matches!(Attestation::pending("a".repeat(MAX_URI_LEN + 1)), Err(Error::BadLength { max: MAX_URI_LEN, .. }));
+    }
+
+    #[test]
+    fn to_bytes() {
+        let bytes = Attestation::bitcoin(449397).to_bytes().unwrap();
+        assert_eq!(&bytes[..TAG_SIZE], BITCOIN_TAG);
+        // payload length, then the height as a varint
+        assert_eq!(&bytes[TAG_SIZE..], b"\x03\xf5\xb6\x1b");
+        assert!(::tests::LARGE_TEST.windows(bytes.len()).any(|w| w == &bytes[..]));
+    }
+
+    #[test]
+    fn oversized_payload() {
+        // Unknown tag claiming a 4GB payload, with no payload following
+        let mut ser = ser::Serializer::new(vec![]);
+        ser.write_fixed_bytes(b"\x01\x02\x03\x04\x05\x06\x07\x08").unwrap();
+        ser.write_uint(0xffff_ffff).unwrap();
+        let bytes = ser.into_inner();
+
+        let mut deser = ser::Deserializer::new(&bytes[..]);
+        assert_matches!(Attestation::deserialize(&mut deser), Err(Error::BadLength { max: MAX_ATTESTATION_LEN, val, .. }) if val == 0xffff_ffff);
+
+        // A payload at the limit is fine
+        let mut ser = ser::Serializer::new(vec![]);
+        let attest = Attestation::Unknown {
+            tag: b"\x01\x02\x03\x04\x05\x06\x07\x08".to_vec(),
+            data: vec![0xaa; MAX_ATTESTATION_LEN]
+        };
+        attest.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let mut deser = ser::Deserializer::new(&bytes[..]);
+        assert_eq!(Attestation::deserialize(&mut deser).unwrap(), attest);
+
+        // The limit can be lowered or raised
+        let options = ser::ParseOptions { max_attestation_len: 100, ..Default::default() };
+        let mut deser = ser::Deserializer::with_options(&bytes[..], options);
+        assert_matches!(Attestation::deserialize(&mut deser), Err(Error::BadLength { max: 100, val, .. }) if val == MAX_ATTESTATION_LEN);
+        let attest = Attestation::Unknown {
+            tag: b"\x01\x02\x03\x04\x05\x06\x07\x08".to_vec(),
+            data: vec![0xaa; 2 * MAX_ATTESTATION_LEN]
+        };
+        let bytes = attest.to_bytes().unwrap();
+        let options = ser::ParseOptions { max_attestation_len: 2 * MAX_ATTESTATION_LEN, ..Default::default() };
+        let mut deser = ser::Deserializer::with_options(&bytes[..], options);
+        assert_eq!(Attestation::deserialize(&mut deser).unwrap(), attest);
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # OpenTimestamps Tool
+//!
+//! Application for maintaining OTS info files: pruning them, and (with the
+//! `calendar` feature) upgrading them.
+//!
+
+// Coding conventions
+#![deny(non_upper_case_globals)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![deny(missing_docs)]
+
+extern crate env_logger;
+extern crate opentimestamps as ots;
+
+use std::{env, fs, process};
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} <command> [arguments]", program);
+    println!();
+    println!("Commands:");
+    println!("    prune <timestamp.ots>  remove pending attestations made redundant by");
+    println!("                           blockchain attestations, rewriting the file");
+    if cfg!(feature = "calendar") {
+        println!("    upgrade [--calendar <pattern>]... <timestamp.ots>");
+        println!("                           fetch upgraded timestamps from calendars, rewriting");
+        println!("                           the file; calendars other than the official ones");
+        println!("                           are only contacted if allowed by a pattern, such");
+        println!("                           as https://*.example.com");
+    }
+    process::exit(1);
+}
+
+fn fail(message: String) -> ! {
+    println!("{}", message);
+    process::exit(1);
+}
+
+fn read(filename: &str) -> ots::DetachedTimestampFile {
+    let fh = match fs::File::open(filename) {
+        Ok(fh) => fh,
+        Err(e) => fail(format!("Failed to open {}: {}", filename, e)),
+    };
+    match ots::DetachedTimestampFile::from_reader(fh) {
+        Ok(ots) => ots,
+        Err(e) => fail(format!("Failed to parse {}: {}", filename, e)),
+    }
+}
+
+/// Writes a new file alongside and moves it into place, so that a failure
+/// part way through can't leave a damaged timestamp
+fn replace(filename: &str, ots: &ots::DetachedTimestampFile) {
+    let temp = format!("{}.tmp", filename);
+    if let Err(e) = ots.to_bytes().and_then(|bytes| Ok(fs::write(&temp, bytes)?)) {
+        let _ = fs::remove_file(&temp);
+        fail(format!("Failed to write {}: {}", temp, e));
+    }
+    if let Err(e) = fs::rename(&temp, filename) {
+        let _ = fs::remove_file(&temp);
+        fail(format!("Failed to replace {}: {}", filename, e));
+    }
+}
+
+fn prune(filename: &str) {
+    let mut ots = read(filename);
+    let removed = match ots.timestamp.prune() {
+        Ok(removed) => removed,
+        Err(e) => fail(format!("Not pruning {}: {}", filename, e)),
+    };
+    if removed > 0 {
+        replace(filename, &ots);
+    }
+    println!("Removed {} branch{} from {}", removed, if removed == 1 { "" } else { "es" }, filename);
+}
+
+/// Upgrades the file from its calendars. The file is only rewritten if at
+/// least one attestation was upgraded; otherwise it is left untouched.
+#[cfg(feature = "calendar")]
+fn upgrade(args: &[&str]) {
+    use ots::calendar::{Allowlist, HttpCalendar, DEFAULT_ALLOWLIST};
+
+    let mut patterns = DEFAULT_ALLOWLIST.to_vec();
+    let mut args = args.iter();
+    let mut filename = None;
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--calendar" => match args.next() {
+                Some(&pattern) => patterns.push(pattern),
+                None => fail("--calendar needs a pattern".to_owned()),
+            },
+            _ if filename.is_none() => filename = Some(arg),
+            _ => fail(format!("Unexpected argument {}", arg)),
+        }
+    }
+    let filename = match filename {
+        Some(filename) => filename,
+        None => fail("No timestamp to upgrade".to_owned()),
+    };
+
+    let mut ots = read(filename);
+    let report = ots.timestamp.upgrade(&HttpCalendar::new(), &Allowlist::new(&patterns));
+    for uri in &report.upgraded {
+        println!("Upgraded from {}", uri);
+    }
+    for uri in &report.skipped {
+        println!("Skipped {}: not an allowed calendar", uri);
+    }
+    for (uri, e) in &report.failed {
+        println!("Failed to upgrade from {}: {}", uri, e);
+    }
+    if report.upgraded.is_empty() {
+        fail(format!("Nothing upgraded; left {} unchanged", filename));
+    }
+    replace(filename, &ots);
+}
+
+fn main() {
+    env_logger::init();
+
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "ots".to_owned());
+    let args: Vec<String> = args.collect();
+    match args.iter().map(|s| &s[..]).collect::<Vec<_>>()[..] {
+        ["prune", filename] => prune(filename),
+        #[cfg(feature = "calendar")]
+        ["upgrade", ref rest @ ..] => upgrade(rest),
+        _ => usage(&program),
+    }
+}
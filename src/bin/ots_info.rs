@@ -23,35 +23,106 @@
 #![deny(missing_docs)]
 
 extern crate env_logger;
+extern crate log;
 extern crate opentimestamps as ots;
 
 use std::{env, fs, process};
 
-fn main() {
-    env_logger::init();
+/// Most lines of the timestamp to show unless `--full` is given
+const DEFAULT_MAX_LINES: usize = 1000;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [options] <timestamp.ots>", program);
+    println!("       {} [options] --hex <hex>", program);
+    println!();
+    println!("Options:");
+    println!("    -v, --verbose        log debug output; use -vv for trace output");
+    println!("    --hex <hex>          read the timestamp from a hex string, not a file");
+    println!("    --max-lines <n>      show at most <n> lines of the timestamp (default {})", DEFAULT_MAX_LINES);
+    println!("    --full               show the whole timestamp, however long");
+    println!("    --max-depth <n>      refuse timestamps nested more than <n> steps deep");
+    println!("    --max-op-length <n>  refuse op arguments longer than <n> bytes");
+    println!("    --max-size <n>       refuse files longer than <n> bytes");
+    process::exit(1);
+}
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <timestamp.ots>", args[0]);
-        process::exit(1);
+fn parse_limit(program: &str, flag: &str, value: Option<String>) -> usize {
+    match value.as_ref().map(|s| s.parse()) {
+        Some(Ok(n)) => n,
+        _ => {
+            println!("{} requires a numeric argument", flag);
+            usage(program);
+        }
     }
+}
 
-    let fh = match fs::File::open(&args[1]) {
-        Ok(fh) => fh,
-        Err(e) => {
-            println!("Failed to open {}: {}", args[1], e);
-            process::exit(1);
+fn main() {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "ots-info".to_owned());
+    let mut options = ots::ser::ParseOptions::default();
+    let mut verbosity = 0;
+    let mut filename = None;
+    let mut hex = None;
+    let mut max_lines = Some(DEFAULT_MAX_LINES);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-v" | "--verbose" => verbosity += 1,
+            "-vv" => verbosity += 2,
+            "--max-depth" => options.max_depth = parse_limit(&program, &arg, args.next()),
+            "--max-op-length" => options.max_op_length = parse_limit(&program, &arg, args.next()),
+            "--max-size" => options.max_size = Some(parse_limit(&program, &arg, args.next())),
+            "--max-lines" => max_lines = Some(parse_limit(&program, &arg, args.next())),
+            "--full" => max_lines = None,
+            "--hex" => match args.next() {
+                Some(s) => hex = Some(s),
+                None => usage(&program),
+            },
+            _ if arg.starts_with('-') => usage(&program),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(&program),
         }
+    }
+    let (filename, hex) = match (filename, hex) {
+        (Some(filename), None) => (filename, None),
+        (None, Some(hex)) => ("hex argument".to_owned(), Some(hex)),
+        _ => usage(&program),
     };
 
-    let ots = match ots::DetachedTimestampFile::from_reader(fh) {
+    // Without any flags, leave logging to be configured by RUST_LOG
+    match verbosity {
+        0 => env_logger::init(),
+        1 => env_logger::Builder::new().filter_level(log::LevelFilter::Debug).init(),
+        _ => env_logger::Builder::new().filter_level(log::LevelFilter::Trace).init(),
+    }
+
+    let parsed = match hex {
+        Some(hex) => match ots::hex::from_hex(&hex) {
+            Ok(bytes) => ots::DetachedTimestampFile::from_reader_with_options(&bytes[..], options),
+            Err(e) => {
+                println!("Failed to decode {}: {}", filename, e);
+                process::exit(1);
+            }
+        },
+        None => match fs::File::open(&filename) {
+            Ok(fh) => ots::DetachedTimestampFile::from_reader_with_options(fh, options),
+            Err(e) => {
+                println!("Failed to open {}: {}", filename, e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let ots = match parsed {
         Ok(ots) => ots,
         Err(e) => {
-            println!("Failed to parse {}: {}", args[1], e);
+            println!("Failed to parse {}: {}", filename, e);
             process::exit(1);
         }
     };
 
-    println!("{}", ots);
+    match max_lines {
+        Some(max_lines) => println!("{:.*}", max_lines, ots),
+        None => println!("{}", ots),
+    }
 }
 
@@ -0,0 +1,247 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # Calendars
+//!
+//! Support for talking to OpenTimestamps calendar servers. A calendar
+//! answers `GET <uri>/timestamp/<commitment>` with the timestamp on the
+//! commitment, serialized without any file header.
+//!
+
+#[cfg(feature = "calendar")]
+use std::io::Read;
+#[cfg(feature = "calendar")]
+use std::time::Duration;
+
+use error::Error;
+#[cfg(feature = "calendar")]
+use hex::Hexed;
+use ser;
+use timestamp::Timestamp;
+
+/// How much of an unexpected response body to keep for error messages
+const SNIPPET_LEN: usize = 80;
+
+/// Content type calendars serve timestamps with
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The calendars contacted by default when upgrading timestamps: those run
+/// by OpenTimestamps and its long-standing partners
+pub const DEFAULT_ALLOWLIST: &[&str] = &[
+    "https://*.calendar.opentimestamps.org",
+    "https://*.calendar.eternitywall.com",
+    "https://*.calendar.catallaxy.com",
+];
+
+/// Something which can fetch the timestamp on a commitment from a calendar
+pub trait CalendarProvider {
+    /// Fetches the timestamp on `commitment` from the calendar at `uri`
+    fn timestamp(&self, uri: &str, commitment: &[u8]) -> Result<Timestamp, Error>;
+}
+
+/// Most bytes of a calendar's response to read; real timestamps are far
+/// smaller
+#[cfg(feature = "calendar")]
+const MAX_RESPONSE_LEN: u64 = 10_000;
+
+/// How long to wait on a calendar before giving up
+#[cfg(feature = "calendar")]
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client fetching timestamps from calendars over HTTP
+#[cfg(feature = "calendar")]
+pub struct HttpCalendar {
+    agent: ureq::Agent
+}
+
+#[cfg(feature = "calendar")]
+impl HttpCalendar {
+    /// Creates a client
+    pub fn new() -> HttpCalendar {
+        HttpCalendar {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+        }
+    }
+}
+
+#[cfg(feature = "calendar")]
+impl Default for HttpCalendar {
+    fn default() -> HttpCalendar {
+        HttpCalendar::new()
+    }
+}
+
+#[cfg(feature = "calendar")]
+impl CalendarProvider for HttpCalendar {
+    fn timestamp(&self, uri: &str, commitment: &[u8]) -> Result<Timestamp, Error> {
+        let url = format!("{}/timestamp/{}", uri.trim_end_matches('/'), Hexed(commitment));
+        debug!("Fetching {}", url);
+        let response = match self.agent.get(&url).set("Accept", "application/vnd.opentimestamps.v1").call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(Error::CalendarUnreachable(format!("{}: {}", url, e)))
+        };
+        let status = response.status();
+        let content_type = response.header("Content-Type").map(str::to_owned);
+        let mut body = vec![];
+        response.into_reader().take(MAX_RESPONSE_LEN).read_to_end(&mut body)?;
+        parse_response(status, content_type.as_deref(), &body, commitment)
+    }
+}
+
+/// The calendars which may be contacted when upgrading a timestamp. The
+/// URIs of pending attestations come from the proof, which may be hostile,
+/// so only calendars the user trusts should be contacted.
+///
+/// Each pattern is a scheme and host such as `https://example.com`; the
+/// host may start with `*.` to allow any subdomain. Ports must match
+/// exactly and paths are ignored.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Allowlist {
+    patterns: Vec<String>
+}
+
+impl Allowlist {
+    /// Creates an allowlist of the given patterns
+    pub fn new(patterns: &[&str]) -> Allowlist {
+        Allowlist {
+            patterns: patterns.iter().map(|pattern| pattern.to_ascii_lowercase()).collect()
+        }
+    }
+
+    /// Whether the calendar at `uri` may be contacted
+    pub fn allows(&self, uri: &str) -> bool {
+        let (scheme, host) = match scheme_and_host(uri) {
+            Some(x) => x,
+            None => return false
+        };
+        self.patterns.iter().any(|pattern| {
+            let (pattern_scheme, pattern_host) = match scheme_and_host(pattern) {
+                Some(x) => x,
+                None => return false
+            };
+            if pattern_scheme != scheme {
+                return false;
+            }
+            match pattern_host.strip_prefix("*.") {
+                Some(domain) => host.len() > domain.len() + 1
+                    && host.ends_with(domain)
+                    && host[..host.len() - domain.len()].ends_with('.'),
+                None => pattern_host == host
+            }
+        })
+    }
+}
+
+impl Default for Allowlist {
+    /// The official calendars, `DEFAULT_ALLOWLIST`
+    fn default() -> Allowlist {
+        Allowlist::new(DEFAULT_ALLOWLIST)
+    }
+}
+
+/// Splits the scheme and host (with any port) out of a URI, lowercased
+fn scheme_and_host(uri: &str) -> Option<(String, String)> {
+    let split = uri.find("://")?;
+    let rest = &uri[split + 3..];
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    // Anything before an `@` is user info, not the host
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    if host.is_empty() {
+        return None;
+    }
+    Some((uri[..split].to_ascii_lowercase(), host.to_ascii_lowercase()))
+}
+
+/// What happened to each pending attestation when upgrading a timestamp
+#[derive(Debug, Default)]
+pub struct UpgradeReport {
+    /// Calendars whose pending attestations were replaced
+    pub upgraded: Vec<String>,
+    /// Calendars not contacted because they aren't on the allowlist
+    pub skipped: Vec<String>,
+    /// Calendars which were contacted but gave no usable timestamp
+    pub failed: Vec<(String, Error)>
+}
+
+/// Parses a calendar's response to a request for the timestamp on
+/// `commitment`. Responses which can't be a timestamp, such as an error
+/// page from a proxy in front of the calendar, are reported as
+/// `Error::CalendarResponse` rather than whatever parsing them as a
+/// timestamp would give.
+pub fn parse_response(status: u16, content_type: Option<&str>, body: &[u8], commitment: &[u8]) -> Result<Timestamp, Error> {
+    let wrong_type = match content_type {
+        Some(content_type) => !content_type.trim().to_ascii_lowercase().starts_with(CONTENT_TYPE),
+        None => false
+    };
+    let looks_like_text = body.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'<');
+    if status != 200 || wrong_type || looks_like_text {
+        return Err(Error::CalendarResponse { status, snippet: snippet(body) });
+    }
+
+    let mut deser = ser::Deserializer::new(body);
+    let timestamp = Timestamp::deserialize(&mut deser, commitment.to_vec())?;
+    deser.check_eof()?;
+    Ok(timestamp)
+}
+
+/// The start of `body` as text, for showing the user
+fn snippet(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&body[..body.len().min(SNIPPET_LEN)]);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist() {
+        let allowlist = Allowlist::default();
+        assert!(allowlist.allows("https://alice.btc.calendar.opentimestamps.org"));
+        assert!(allowlist.allows("https://finney.calendar.eternitywall.com/"));
+        assert!(allowlist.allows("HTTPS://Bob.BTC.Calendar.OpenTimestamps.org"));
+
+        assert!(!allowlist.allows("https://calendar.opentimestamps.org"));
+        assert!(!allowlist.allows("http://alice.btc.calendar.opentimestamps.org"));
+        assert!(!allowlist.allows("https://evilcalendar.opentimestamps.org"));
+        assert!(!allowlist.allows("https://alice.btc.calendar.opentimestamps.org.example.com"));
+        assert!(!allowlist.allows("https://alice.btc.calendar.opentimestamps.org@example.com"));
+        assert!(!allowlist.allows("https://alice.btc.calendar.opentimestamps.org:8080"));
+        assert!(!allowlist.allows("alice.btc.calendar.opentimestamps.org"));
+
+        let allowlist = Allowlist::new(&["http://localhost:14788"]);
+        assert!(allowlist.allows("http://localhost:14788/calendar"));
+        assert!(!allowlist.allows("http://localhost:14789"));
+        assert!(!allowlist.allows("https://alice.btc.calendar.opentimestamps.org"));
+    }
+
+    #[test]
+    fn parse_response() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let commitment = ots.timestamp.start_digest.clone();
+        let mut ser = ser::Serializer::new(vec![]);
+        ots.timestamp.serialize(&mut ser).unwrap();
+        let body = ser.into_inner();
+
+        assert_eq!(super::parse_response(200, Some("application/octet-stream"), &body, &commitment).unwrap(), ots.timestamp);
+        assert_eq!(super::parse_response(200, None, &body, &commitment).unwrap(), ots.timestamp);
+
+        let html = b"<html>\r\n<head><title>502 Bad Gateway</title></head>\r\n<body>nginx</body>\r\n</html>\r\n";
+        for &(status, content_type) in &[(200, Some("text/html")), (200, None), (502, Some("text/html"))] {
+            assert_matches!(super::parse_response(status, content_type, html, &commitment),
+                Err(Error::CalendarResponse { status: got, ref snippet }) if got == status && snippet.starts_with("<html> <head><title>502 Bad Gateway"));
+        }
+
+        // Calendars answer 404 for commitments which aren't yet timestamped
+        assert_matches!(super::parse_response(404, Some("text/plain"), b"Pending confirmation in Bitcoin blockchain", &commitment),
+            Err(Error::CalendarResponse { status: 404, ref snippet }) if snippet == "Pending confirmation in Bitcoin blockchain");
+    }
+}
@@ -15,9 +15,13 @@
 //!
 
 use std::error;
-use std::{fmt, io};
+use std::{fmt, io, ops};
 use std::string::FromUtf8Error;
 
+use attestation::BlockHeight;
+use hex::Hexed;
+use op::Op;
+
 /// Library-wide error structure
 #[allow(missing_docs)]
 #[derive(Debug)]
@@ -26,18 +30,90 @@ pub enum Error {
     StackOverflow,
     /// A URI had a character we don't like
     InvalidUriChar(char),
+    /// A hex string had a character that isn't a hex digit
+    InvalidHexChar(char),
+    /// A hex string had an odd number of digits
+    OddHexLength(usize),
     /// A digest type tag was not recognized
     BadDigestTag(u8),
+    /// A digest length fits more than one digest type
+    AmbiguousDigestLen(usize),
+    /// A digest length fits no digest type
+    UnknownDigestLen(usize),
     /// Decoded an op tag that we don't recognize
     BadOpTag(u8),
+    /// Input was empty where an OTS file was expected
+    EmptyInput,
     /// OTS file began with invalid magic bytes
     BadMagic(Vec<u8>),
     /// OTS file has version we don't understand
-    BadVersion(usize),
+    BadVersion { got: usize, supported: ops::RangeInclusive<usize> },
+    /// An encoded unsigned integer was too large to decode
+    UintOverflow,
     /// A byte vector had an invalid length
     BadLength { min: usize, max: usize, val: usize },
     /// Expected EOF but didn't get it
     TrailingBytes,
+    /// Input ended partway through a length-prefixed byte vector
+    Truncated { expected: usize, got: usize },
+    /// Input ended partway through the argument of an op
+    TruncatedOp { tag: u8, expected: usize, got: usize },
+    /// Executing an op produced a result longer than the op length limit
+    OpOutputTooLong { op: &'static str, len: usize },
+    /// Input was longer than the configured maximum size
+    SizeLimit(usize),
+    /// A buffer was too small to serialize into
+    BufferTooSmall { needed: usize, len: usize },
+    /// Input had more attestations than the configured maximum
+    TooManyAttestations(usize),
+    /// A blockchain attestation gave a block height too large to be real
+    BadBlockHeight(usize),
+    /// A timestamp path ended in an op rather than an attestation
+    DanglingOp,
+    /// A step had a number of following steps inappropriate for its type
+    BadBranchCount { step: &'static str, count: usize },
+    /// Tried to change the commitment under a blockchain attestation
+    AlreadyAttested,
+    /// Tried to prune a timestamp with no blockchain attestation
+    NotAttested,
+    /// No attestation in the timestamp satisfied the condition for keeping it
+    NothingRetained,
+    /// A digest was not the commitment it was expected to be
+    CommitmentMismatch { expected: Vec<u8>, got: Vec<u8> },
+    /// A document digest matched the timestamp only when reversed, so it was
+    /// probably stored in the wrong byte order
+    ReversedDigest,
+    /// A step's output was not what executing it on its input gives, found
+    /// `depth` steps below the step the check started from
+    OutputMismatch { depth: usize, expected: Vec<u8>, got: Vec<u8> },
+    /// A timestamp relied on a digest type we consider weak
+    WeakDigest(&'static str),
+    /// A document digest was the wrong length for its declared digest type,
+    /// which would make the file claim a hash function it wasn't made with
+    DigestTypeMismatch { digest_type: &'static str, expected: usize, got: usize },
+    /// No attestation in the timestamp is on the given commitment
+    CommitmentNotFound,
+    /// A block header provider could not supply a block
+    Provider(String),
+    /// The path to a Bitcoin attestation contained no transaction
+    MissingTransaction { height: BlockHeight },
+    /// The transaction in the path to a Bitcoin attestation is not in the
+    /// block the attestation names
+    TxNotInBlock { height: BlockHeight },
+    /// A Bitcoin attestation did not match the Merkle root of its block
+    MerkleRootMismatch { height: BlockHeight },
+    /// A Bitcoin attestation matched the Merkle root of its block only when
+    /// reversed, so the root was probably given in display byte order
+    ReversedRoot { height: BlockHeight },
+    /// A calendar gave a response which isn't a timestamp, such as an error
+    /// page; `snippet` is the start of the body
+    CalendarResponse { status: u16, snippet: String },
+    /// A calendar could not be reached
+    CalendarUnreachable(String),
+    /// A JSON timestamp did not have the expected shape
+    BadJson(String),
+    /// Reading the input took longer than allowed
+    Timeout,
     /// UTF8
     Utf8(FromUtf8Error),
     /// I/O error
@@ -52,7 +128,48 @@ impl From<FromUtf8Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        Error::Io(e)
+        if e.kind() == io::ErrorKind::TimedOut {
+            Error::Timeout
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl Error {
+    /// Whether the input ended too soon, so that more of the same input
+    /// might parse, e.g. after waiting on a network stream
+    pub fn is_truncation(&self) -> bool {
+        match *self {
+            Error::EmptyInput | Error::Truncated { .. } | Error::TruncatedOp { .. } => true,
+            Error::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false
+        }
+    }
+
+    /// Whether the input is structurally invalid, so that retrying won't
+    /// help. Inputs which are valid but exceed a configured limit are not
+    /// considered malformed.
+    pub fn is_malformed(&self) -> bool {
+        matches!(*self,
+            Error::InvalidUriChar(_)
+                | Error::InvalidHexChar(_)
+                | Error::OddHexLength(_)
+                | Error::BadDigestTag(_)
+                | Error::AmbiguousDigestLen(_)
+                | Error::UnknownDigestLen(_)
+                | Error::BadOpTag(_)
+                | Error::BadMagic(_)
+                | Error::BadVersion { .. }
+                | Error::BadLength { .. }
+                | Error::UintOverflow
+                | Error::TrailingBytes
+                | Error::BadBlockHeight(_)
+                | Error::DanglingOp
+                | Error::BadBranchCount { .. }
+                | Error::BadJson(_)
+                | Error::Utf8(_)
+        )
     }
 }
 
@@ -61,12 +178,48 @@ impl fmt::Display for Error {
         match *self {
             Error::StackOverflow => f.write_str("recursion limit reached"),
             Error::InvalidUriChar(c) => write!(f, "invalid character `{}` in URI", c),
+            Error::InvalidHexChar(c) => write!(f, "invalid character `{}` in hex string", c.escape_debug()),
+            Error::OddHexLength(len) => write!(f, "hex string has odd length {}", len),
             Error::BadDigestTag(t) => write!(f, "invalid digest tag 0x{:02x}", t),
-            Error::BadOpTag(t) => write!(f, "invalid op tag 0x{:02x}", t),
+            Error::AmbiguousDigestLen(len) => write!(f, "digest length {} fits more than one digest type", len),
+            Error::UnknownDigestLen(len) => write!(f, "digest length {} fits no known digest type", len),
+            Error::BadOpTag(t) => match Op::describe_tag(t) {
+                Some(name) => write!(f, "op 0x{:02x} ({}) is not supported by this build", t, name),
+                None => write!(f, "invalid op tag 0x{:02x}", t)
+            },
+            Error::EmptyInput => f.write_str("empty input, expected a .ots file"),
             Error::BadMagic(ref x) => write!(f, "bad magic bytes `{:?}`, is this a timestamp file?", x),
-            Error::BadVersion(v) => write!(f, "version {} timestamps not understood", v),
+            Error::BadVersion { got, ref supported } => write!(f, "version {} timestamps not understood (supported: {} to {})", got, supported.start(), supported.end()),
             Error::BadLength { min, max, val } => write!(f, "length {} should be between {} and {} inclusive", val, min, max),
+            Error::UintOverflow => f.write_str("encoded integer too large"),
             Error::TrailingBytes => f.write_str("expected eof not"), // lol
+            Error::Truncated { expected, got } => write!(f, "input truncated: expected {} bytes, got {}", expected, got),
+            Error::TruncatedOp { tag, expected, got } => write!(f, "argument of op 0x{:02x} truncated: expected {} bytes, got {}", tag, expected, got),
+            Error::OpOutputTooLong { op, len } => write!(f, "{} op produced {} bytes, exceeding the op length limit", op, len),
+            Error::SizeLimit(max) => write!(f, "input exceeds size limit of {} bytes", max),
+            Error::BufferTooSmall { needed, len } => write!(f, "serialization needs {} bytes but buffer has {}", needed, len),
+            Error::TooManyAttestations(max) => write!(f, "input exceeds limit of {} attestations", max),
+            Error::BadBlockHeight(height) => write!(f, "block height {} out of range", height),
+            Error::DanglingOp => f.write_str("timestamp path ends in an op rather than an attestation"),
+            Error::BadBranchCount { step, count } => write!(f, "{} step cannot be followed by {} steps", step, count),
+            Error::NotAttested => f.write_str("timestamp has no blockchain attestation"),
+            Error::NothingRetained => f.write_str("no attestation would be left in the timestamp"),
+            Error::AlreadyAttested => f.write_str("timestamp already has a blockchain attestation"),
+            Error::CommitmentMismatch { ref expected, ref got } => write!(f, "digest {} does not match expected commitment {}", Hexed(got), Hexed(expected)),
+            Error::ReversedDigest => f.write_str("document digest matches only when reversed; check its byte order"),
+            Error::WeakDigest(name) => write!(f, "refusing weak {} digest", name),
+            Error::DigestTypeMismatch { digest_type, expected, got } => write!(f, "{} digests are {} bytes, not {}", digest_type, expected, got),
+            Error::OutputMismatch { depth, ref expected, ref got } => write!(f, "step output {} at depth {} does not match expected {}", Hexed(got), depth, Hexed(expected)),
+            Error::CommitmentNotFound => f.write_str("no attestation on the given commitment"),
+            Error::Provider(ref msg) => write!(f, "block header provider failed: {}", msg),
+            Error::MissingTransaction { height } => write!(f, "no transaction leads to attestation from block {}", height),
+            Error::TxNotInBlock { height } => write!(f, "transaction is not in block {}", height),
+            Error::MerkleRootMismatch { height } => write!(f, "attestation does not match Merkle root of block {}", height),
+            Error::ReversedRoot { height } => write!(f, "attestation matches Merkle root of block {} only when reversed; check the root's byte order", height),
+            Error::CalendarResponse { status, ref snippet } => write!(f, "calendar gave status {} and no timestamp: {}", status, snippet),
+            Error::CalendarUnreachable(ref msg) => write!(f, "calendar unreachable: {}", msg),
+            Error::BadJson(ref msg) => write!(f, "bad JSON timestamp: {}", msg),
+            Error::Timeout => f.write_str("timed out reading input"),
             Error::Utf8(ref e) => fmt::Display::fmt(e, f),
             Error::Io(ref e) => fmt::Display::fmt(e, f)
         }
@@ -84,3 +237,30 @@ impl error::Error for Error {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classification() {
+        use tests::SMALL_TEST;
+        use DetachedTimestampFile;
+
+        let truncated = DetachedTimestampFile::from_reader(&SMALL_TEST[..SMALL_TEST.len() - 10]).unwrap_err();
+        assert!(truncated.is_truncation());
+        assert!(!truncated.is_malformed());
+
+        let empty = DetachedTimestampFile::from_reader(&b""[..]).unwrap_err();
+        assert!(empty.is_truncation());
+        assert!(!empty.is_malformed());
+
+        let mut bad_magic = SMALL_TEST.to_vec();
+        bad_magic[1] ^= 0xff;
+        let bad_magic = DetachedTimestampFile::from_reader(&bad_magic[..]).unwrap_err();
+        assert!(!bad_magic.is_truncation());
+        assert!(bad_magic.is_malformed());
+
+        assert!(!Error::StackOverflow.is_truncation());
+        assert!(!Error::StackOverflow.is_malformed());
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # Esplora
+//!
+//! A `BlockHeaderProvider` which looks up blocks using the REST API of an
+//! Esplora server, such as blockstream.info or mempool.space, for verifying
+//! timestamps without running a Bitcoin node.
+//!
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use attestation::{BlockHeaderProvider, BlockHeight};
+use error::Error;
+use hex;
+
+/// How long to wait on the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client for an Esplora server
+pub struct Esplora {
+    base_url: String,
+    agent: ureq::Agent
+}
+
+impl Esplora {
+    /// Creates a client for the API at `base_url`, for example
+    /// `https://mempool.space/api`
+    pub fn new(base_url: &str) -> Esplora {
+        Esplora {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+        }
+    }
+
+    /// Fetches `path` from the server as text
+    fn get(&self, path: &str) -> Result<String, Error> {
+        let url = format!("{}{}", self.base_url, path);
+        debug!("Fetching {}", url);
+        let response = self.agent.get(&url)
+            .call()
+            .map_err(|e| Error::Provider(format!("{}: {}", url, e)))?;
+        Ok(response.into_string()?)
+    }
+
+    /// Fetches the hash and JSON description of the block at `height`
+    fn block(&self, height: BlockHeight) -> Result<(String, Value), Error> {
+        let hash = self.get(&format!("/block-height/{}", height))?.trim().to_owned();
+        let block = self.get(&format!("/block/{}", hash))?;
+        let block = serde_json::from_str(&block)
+            .map_err(|e| Error::Provider(format!("bad block {}: {}", hash, e)))?;
+        Ok((hash, block))
+    }
+}
+
+impl BlockHeaderProvider for Esplora {
+    fn merkle_root(&self, height: BlockHeight) -> Result<[u8; 32], Error> {
+        let (hash, block) = self.block(height)?;
+        let root = block.get("merkle_root")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::Provider(format!("block {} has no Merkle root", hash)))?;
+        let root = hex::from_hex(root)?;
+        if root.len() != 32 {
+            return Err(Error::Provider(format!("block {} has a bad Merkle root", hash)));
+        }
+
+        // Esplora gives the root in display order, the reverse of the header
+        let mut header_root = [0; 32];
+        for (byte, &root_byte) in header_root.iter_mut().zip(root.iter().rev()) {
+            *byte = root_byte;
+        }
+        Ok(header_root)
+    }
+
+    fn block_time(&self, height: BlockHeight) -> Result<u32, Error> {
+        let (hash, block) = self.block(height)?;
+        block.get("timestamp")
+            .and_then(Value::as_u64)
+            .and_then(|time| u32::try_from(time).ok())
+            .ok_or_else(|| Error::Provider(format!("block {} has no valid time", hash)))
+    }
+}
+
+#[cfg(test)]
+#[path = "../tests/common/mock_http.rs"]
+mod mock_http;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves `count` requests on a local port, looking up each path in
+    /// `pages`, and returns the base URL
+    fn mock_server(pages: Vec<(String, String)>, count: usize) -> String {
+        mock_http::mock_server(count, move |path| match pages.iter().find(|page| page.0 == path) {
+            Some(page) => mock_http::response("200 OK", "text/plain", page.1.as_bytes()),
+            None => mock_http::response("404 Not Found", "text/plain", b"")
+        })
+    }
+
+    #[test]
+    fn verify_large_test() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        // The Merkle roots of the LARGE_TEST blocks, in display order
+        let pages = vec![
+            ("/block-height/449399".to_owned(), "hash449399".to_owned()),
+            ("/block/hash449399".to_owned(), r#"{"height":449399,"merkle_root":"5e1feb6562415b0b37732de0a88c5dfba62ee51e465f0c143befe81467a21d1a","timestamp":1484688137}"#.to_owned()),
+            ("/block-height/449397".to_owned(), "hash449397".to_owned()),
+            ("/block/hash449397".to_owned(), r#"{"height":449397,"merkle_root":"18f21f7933f1fcf0bd7648bcdc9032986988c32f44cd4e60a31dbcd6a0a8177c","timestamp":1484687409}"#.to_owned()),
+        ];
+        let esplora = Esplora::new(&mock_server(pages.clone(), 4));
+        assert_eq!(ots.timestamp.verify_bitcoin(&esplora).unwrap(), vec![BlockHeight(449399), BlockHeight(449397)]);
+
+        let esplora = Esplora::new(&mock_server(pages, 2));
+        assert_eq!(esplora.block_time(BlockHeight(449397)).unwrap(), 1484687409);
+
+        let esplora = Esplora::new(&mock_server(vec![], 1));
+        assert_matches!(esplora.merkle_root(BlockHeight(449397)), Err(Error::Provider(_)));
+    }
+}
@@ -16,6 +16,8 @@
 
 use std::fmt::{self, Write};
 
+use error::Error;
+
 /// Wrapper around a byteslice that allows formatting as hex
 pub struct Hexed<'a>(pub &'a [u8]);
 
@@ -24,16 +26,27 @@ static CHARS: [char; 16] = [
     '8', '9', 'a', 'b', 'c', 'd', 'e', 'f'
 ];
 
-impl<'a> fmt::Debug for Hexed<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+static UPPER_CHARS: [char; 16] = [
+    '0', '1', '2', '3', '4', '5', '6', '7',
+    '8', '9', 'A', 'B', 'C', 'D', 'E', 'F'
+];
+
+impl<'a> Hexed<'a> {
+    fn fmt_with(&self, f: &mut fmt::Formatter, chars: &[char; 16]) -> fmt::Result {
         for byte in self.0 {
-            f.write_char(CHARS[(*byte as usize) >> 4])?;
-            f.write_char(CHARS[(*byte as usize) & 0x0f])?;
+            f.write_char(chars[(*byte as usize) >> 4])?;
+            f.write_char(chars[(*byte as usize) & 0x0f])?;
         }
         Ok(())
     }
 }
 
+impl<'a> fmt::Debug for Hexed<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &CHARS)
+    }
+}
+
 impl<'a> fmt::Display for Hexed<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(self, f)
@@ -46,3 +59,65 @@ impl<'a> fmt::LowerHex for Hexed<'a> {
     }
 }
 
+
+impl<'a> fmt::UpperHex for Hexed<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &UPPER_CHARS)
+    }
+}
+
+/// Decodes a hex string, in either case. Since hex is often pasted from
+/// elsewhere, surrounding whitespace, a byte order mark and a `0x` prefix
+/// are all skipped; anything else which isn't hex is an error.
+pub fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    let s = s.trim_start_matches('\u{feff}').trim_matches(|c: char| c.is_ascii_whitespace());
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+
+    let mut ret = Vec::with_capacity(s.len() / 2);
+    let mut high = None;
+    for c in s.chars() {
+        let nibble = c.to_digit(16).ok_or(Error::InvalidHexChar(c))? as u8;
+        high = match high {
+            None => Some(nibble),
+            Some(h) => {
+                ret.push(h << 4 | nibble);
+                None
+            }
+        };
+    }
+    if high.is_some() {
+        return Err(Error::OddHexLength(s.len()));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case() {
+        let data = [0x01, 0xab, 0xcd, 0xef];
+        assert_eq!(format!("{:X}", Hexed(&[0xab])), "AB");
+        assert_eq!(format!("{:X}", Hexed(&data)), "01ABCDEF");
+        assert_eq!(format!("{:x}", Hexed(&data)), "01abcdef");
+        assert_eq!(format!("{}", Hexed(&data)), "01abcdef");
+        assert_eq!(format!("{:?}", Hexed(&data)), "01abcdef");
+    }
+
+    #[test]
+    fn from_hex() {
+        let data = vec![0x01, 0xab, 0xcd, 0xef];
+        assert_eq!(super::from_hex("01abcdef").unwrap(), data);
+        assert_eq!(super::from_hex("01ABcdEF").unwrap(), data);
+        assert_eq!(super::from_hex("\n  01abcdef\r\n\n").unwrap(), data);
+        assert_eq!(super::from_hex("0x01abcdef").unwrap(), data);
+        assert_eq!(super::from_hex("\u{feff}0X01abcdef\n").unwrap(), data);
+        assert!(super::from_hex(" \t").unwrap().is_empty());
+
+        for &(s, bad) in &[("01ab cdef", ' '), ("01abxdef", 'x'), ("01ab\ncdef", '\n'), ("0x0x01", 'x')] {
+            assert_matches!(super::from_hex(s), Err(Error::InvalidHexChar(c)) if c == bad);
+        }
+        assert_matches!(super::from_hex("0x01abc"), Err(Error::OddHexLength(5)));
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # JSON
+//!
+//! Conversion of timestamp files to and from JSON. The shape is modelled on
+//! the `toJson` output of the OpenTimestamps JavaScript library, but has
+//! not been checked against real output from it, so don't rely on the two
+//! being interchangeable. A timestamp is a tree of nodes, each holding a
+//! message, the attestations on it and the ops leading from it to further
+//! nodes:
+//!
+//! ```text
+//! {
+//!   "hash": "<document digest>",
+//!   "hash_op": "sha256",
+//!   "timestamp": {
+//!     "attestations": [
+//!       { "type": "PendingAttestation", "param": "https://..." },
+//!       { "type": "BitcoinBlockHeaderAttestation", "param": 358391 }
+//!     ],
+//!     "ops": [
+//!       { "op": "append", "arg": "<hex>", "result": "<hex>", "timestamp": { ... } }
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+//! All bytes are lowercase hex. Our forks have no counterpart: a node with
+//! more than one attestation or op corresponds to a fork. Fields we don't
+//! use, such as `fork` or `tx`, are ignored on input.
+//!
+
+use std::convert::TryFrom;
+
+use serde_json::{Map, Value};
+
+use attestation::{Attestation, Chain};
+use error::Error;
+use hex::{self, Hexed};
+use op::Op;
+use ser::{DetachedTimestampFile, DigestType};
+use timestamp::{Step, StepData, Timestamp};
+
+/// Converts a timestamp file to JSON
+pub fn to_value(file: &DetachedTimestampFile) -> Value {
+    let mut json = Map::new();
+    json.insert("hash".to_owned(), hex_value(&file.timestamp.start_digest));
+    json.insert("hash_op".to_owned(), Value::from(file.digest_type.name()));
+    json.insert("timestamp".to_owned(), node_to_value(&file.timestamp.first_step));
+    Value::Object(json)
+}
+
+/// Converts a timestamp file to a JSON string
+pub fn to_string(file: &DetachedTimestampFile) -> String {
+    to_value(file).to_string()
+}
+
+/// Reads a timestamp file from JSON. The ops are re-executed, and any
+/// `result` given for them must match.
+pub fn from_value(json: &Value) -> Result<DetachedTimestampFile, Error> {
+    let digest_type = match field(json, "hash_op")?.as_str() {
+        Some("sha1") => DigestType::Sha1,
+        Some("sha256") => DigestType::Sha256,
+        Some("ripemd160") => DigestType::Ripemd160,
+        _ => return Err(bad("unknown hash_op"))
+    };
+    let digest = hex_field(json, "hash")?;
+    let first_step = value_to_step(field(json, "timestamp")?, &digest)?;
+    let file = DetachedTimestampFile::from_timestamp(digest_type, Timestamp {
+        start_digest: digest,
+        first_step
+    })?;
+    file.timestamp.validate()?;
+    file.timestamp.verify_outputs()?;
+    Ok(file)
+}
+
+/// Reads a timestamp file from a JSON string
+pub fn from_str(s: &str) -> Result<DetachedTimestampFile, Error> {
+    let json: Value = serde_json::from_str(s).map_err(|e| Error::BadJson(e.to_string()))?;
+    from_value(&json)
+}
+
+fn bad(msg: &str) -> Error {
+    Error::BadJson(msg.to_owned())
+}
+
+fn hex_value(data: &[u8]) -> Value {
+    Value::from(Hexed(data).to_string())
+}
+
+fn field<'a>(json: &'a Value, name: &str) -> Result<&'a Value, Error> {
+    json.get(name).ok_or_else(|| Error::BadJson(format!("missing field `{}`", name)))
+}
+
+fn hex_field(json: &Value, name: &str) -> Result<Vec<u8>, Error> {
+    let s = field(json, name)?
+        .as_str()
+        .ok_or_else(|| Error::BadJson(format!("field `{}` is not a string", name)))?;
+    // `hex::from_hex` is lenient with pasted input, allowing whitespace and
+    // a `0x` prefix, but hex fields in this format are bare hex
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::BadJson(format!("field `{}` is not hex", name)));
+    }
+    hex::from_hex(s).map_err(|e| Error::BadJson(format!("field `{}` is not hex: {}", name, e)))
+}
+
+/// Gathers the attestations and ops on the message `step` starts from. Forks
+/// are transparent, their branches all belonging to the same node.
+fn collect_node(step: &Step, attestations: &mut Vec<Value>, ops: &mut Vec<Value>) {
+    match step.data {
+        StepData::Fork => for next in &step.next {
+            collect_node(next, attestations, ops);
+        },
+        StepData::Attestation(ref attest) => attestations.push(attestation_to_value(attest)),
+        // The schema has no way to say that something is missing
+        StepData::Truncated => {}
+        StepData::Op(ref op) => {
+            let arg = match *op {
+                Op::Append(ref data) | Op::Prepend(ref data) => &data[..],
+                _ => &[]
+            };
+            let mut json = Map::new();
+            json.insert("op".to_owned(), Value::from(op.name()));
+            json.insert("arg".to_owned(), hex_value(arg));
+            json.insert("result".to_owned(), hex_value(&step.output));
+            json.insert("timestamp".to_owned(), node_to_value(&step.next[0]));
+            ops.push(Value::Object(json));
+        }
+    }
+}
+
+fn node_to_value(step: &Step) -> Value {
+    let mut attestations = vec![];
+    let mut ops = vec![];
+    collect_node(step, &mut attestations, &mut ops);
+
+    let mut json = Map::new();
+    if !attestations.is_empty() {
+        json.insert("attestations".to_owned(), Value::Array(attestations));
+    }
+    if !ops.is_empty() {
+        json.insert("ops".to_owned(), Value::Array(ops));
+    }
+    Value::Object(json)
+}
+
+fn attestation_to_value(attest: &Attestation) -> Value {
+    let mut json = Map::new();
+    match *attest {
+        Attestation::Blockchain { chain, height } => {
+            let name = match chain {
+                Chain::Bitcoin => "BitcoinBlockHeaderAttestation",
+                Chain::Litecoin => "LitecoinBlockHeaderAttestation"
+            };
+            json.insert("type".to_owned(), Value::from(name));
+            json.insert("param".to_owned(), Value::from(height.0));
+        }
+        Attestation::Pending { ref uri } => {
+            json.insert("type".to_owned(), Value::from("PendingAttestation"));
+            json.insert("param".to_owned(), Value::from(uri.as_str()));
+        }
+        Attestation::Unknown { ref tag, ref data } => {
+            json.insert("type".to_owned(), Value::from("UnknownAttestation"));
+            json.insert("tag".to_owned(), hex_value(tag));
+            json.insert("param".to_owned(), hex_value(data));
+        }
+    }
+    Value::Object(json)
+}
+
+fn value_to_attestation(json: &Value) -> Result<Attestation, Error> {
+    let height = || field(json, "param")?
+        .as_u64()
+        .and_then(|h| u32::try_from(h).ok())
+        .ok_or_else(|| bad("attestation height is not a number"));
+    match field(json, "type")?.as_str() {
+        Some("BitcoinBlockHeaderAttestation") => Ok(Attestation::bitcoin(height()?)),
+        Some("LitecoinBlockHeaderAttestation") => Ok(Attestation::litecoin(height()?)),
+        Some("PendingAttestation") => {
+            let uri = field(json, "param")?
+                .as_str()
+                .ok_or_else(|| bad("pending attestation URI is not a string"))?;
+            Attestation::pending(uri.to_owned())
+        }
+        Some("UnknownAttestation") => Ok(Attestation::Unknown {
+            tag: hex_field(json, "tag")?,
+            data: hex_field(json, "param")?
+        }),
+        _ => Err(bad("unknown attestation type"))
+    }
+}
+
+fn value_to_op(json: &Value) -> Result<Op, Error> {
+    let name = field(json, "op")?.as_str().ok_or_else(|| bad("op name is not a string"))?;
+    match name {
+        "sha1" => Ok(Op::Sha1),
+        "sha256" => Ok(Op::Sha256),
+        "ripemd160" => Ok(Op::Ripemd160),
+        "hexlify" => Ok(Op::Hexlify),
+        "reverse" => Ok(Op::Reverse),
+        "append" | "prepend" => {
+            let arg = hex_field(json, "arg")?;
+            if arg.is_empty() || arg.len() > ::op::MAX_OP_LENGTH {
+                return Err(Error::BadLength { min: 1, max: ::op::MAX_OP_LENGTH, val: arg.len() });
+            }
+            Ok(if name == "append" { Op::Append(arg) } else { Op::Prepend(arg) })
+        }
+        _ => Err(Error::BadJson(format!("unknown op `{}`", name)))
+    }
+}
+
+/// Builds the step for a node whose message is `input`
+fn value_to_step(json: &Value, input: &[u8]) -> Result<Step, Error> {
+    let mut branches = vec![];
+    if let Some(attestations) = json.get("attestations") {
+        for attest in attestations.as_array().ok_or_else(|| bad("attestations is not an array"))? {
+            branches.push(Step {
+                data: StepData::Attestation(value_to_attestation(attest)?),
+                output: input.to_vec(),
+                next: vec![]
+            });
+        }
+    }
+    if let Some(ops) = json.get("ops") {
+        for op_json in ops.as_array().ok_or_else(|| bad("ops is not an array"))? {
+            let op = value_to_op(op_json)?;
+            // A given result is recorded as the output, to be checked once
+            // the whole timestamp is built
+            let output = if op_json.get("result").is_some() {
+                hex_field(op_json, "result")?
+            } else {
+                op.execute_checked(input, ::op::MAX_OP_LENGTH)?
+            };
+            let next = value_to_step(field(op_json, "timestamp")?, &output)?;
+            branches.push(Step {
+                data: StepData::Op(op),
+                output,
+                next: vec![next]
+            });
+        }
+    }
+
+    match branches.len() {
+        0 => Err(bad("timestamp node has no attestations or ops")),
+        1 => Ok(branches.pop().unwrap()),
+        _ => Ok(Step {
+            data: StepData::Fork,
+            output: input.to_vec(),
+            next: branches
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `SMALL_TEST` in our JSON shape, converted by hand
+    const SMALL_TEST_JSON: &str = include_str!("../tests/data/small_test.json");
+
+    #[test]
+    fn json_round_trip() {
+        let ots = DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let parsed = from_str(SMALL_TEST_JSON).unwrap();
+        assert_eq!(parsed, ots);
+
+        let expected: Value = serde_json::from_str(SMALL_TEST_JSON).unwrap();
+        assert_eq!(to_value(&parsed), expected);
+        assert_eq!(from_str(&to_string(&parsed)).unwrap(), ots);
+
+        // Results which don't match the ops are rejected
+        let tampered = SMALL_TEST_JSON.replace("\"result\": \"e1ae951b", "\"result\": \"e1ae951c");
+        assert_matches!(from_str(&tampered), Err(Error::OutputMismatch { .. }));
+
+        // The document digest has to fit the declared hash
+        let tampered = SMALL_TEST_JSON.replace("\"hash_op\": \"sha256\"", "\"hash_op\": \"sha1\"");
+        assert_matches!(from_str(&tampered), Err(Error::DigestTypeMismatch { digest_type: "sha1", expected: 20, got: 32 }));
+
+        // Hex fields are bare lowercase or uppercase hex, nothing else
+        for bad_arg in &["+a", "0x7a4605de0a5b37cb211759c6812bfe2e", " 7a4605de0a5b37cb211759c6812bfe2e", "7a4"] {
+            let tampered = SMALL_TEST_JSON.replace("\"7a4605de0a5b37cb211759c6812bfe2e\"", &format!("\"{}\"", bad_arg));
+            assert_matches!(from_str(&tampered), Err(Error::BadJson(_)), "for {:?}", bad_arg);
+        }
+    }
+}
@@ -23,12 +23,42 @@
 #![deny(unused_mut)]
 #![deny(missing_docs)]
 
+#[cfg(feature = "arbitrary")] extern crate arbitrary;
 extern crate bitcoin_hashes;
+#[cfg(feature = "flate2")] extern crate flate2;
 #[macro_use] extern crate log;
+#[cfg(feature = "mmap")] extern crate memmap2;
+#[cfg(feature = "rayon")] extern crate rayon;
+#[cfg(any(feature = "serde", feature = "esplora"))] extern crate serde_json;
+#[cfg(any(feature = "esplora", feature = "calendar"))] extern crate ureq;
+
+/// Asserts that an expression matches a pattern, with an optional guard,
+/// printing the value if it doesn't. Any further arguments are formatted
+/// into the failure message.
+#[cfg(test)]
+macro_rules! assert_matches {
+    ($e:expr, $($p:pat)|+ $(if $guard:expr)?) => {
+        match $e {
+            $($p)|+ $(if $guard)? => {}
+            x => panic!("unexpected result {:?}", x),
+        }
+    };
+    ($e:expr, $($p:pat)|+ $(if $guard:expr)?, $($arg:tt)+) => {
+        match $e {
+            $($p)|+ $(if $guard)? => {}
+            x => panic!("unexpected result {:?}: {}", x, format_args!($($arg)+)),
+        }
+    };
+}
 
 pub mod attestation;
+pub mod calendar;
 pub mod error;
+#[cfg(feature = "esplora")]
+pub mod esplora;
 pub mod hex;
+#[cfg(feature = "serde")]
+pub mod json;
 pub mod op;
 pub mod timestamp;
 pub mod ser;
@@ -36,11 +66,17 @@ pub mod ser;
 pub use ser::DetachedTimestampFile;
 pub use timestamp::Timestamp;
 
+#[cfg(test)]
+#[path = "../tests/common/temp_path.rs"]
+mod temp_path;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{env, fs};
+    use std::path::{Path, PathBuf};
 
-    const SMALL_TEST: &'static [u8] = b"\
+    pub const SMALL_TEST: &[u8] = b"\
 \x00\x4f\x70\x65\x6e\x54\x69\x6d\x65\x73\x74\x61\x6d\x70\x73\x00\x00\x50\x72\x6f\x6f\x66\x00\xbf\x89\xe2\xe8\x84\xe8\x92\
 \x94\x01\x08\xa7\x0d\xfe\x69\xc5\xa0\xd6\x28\x16\x78\x1a\xbb\x6e\x17\x77\x85\x47\x18\x62\x4a\x0d\x19\x42\x31\xad\xb1\x4c\
 \x32\xee\x54\x38\xa4\xf0\x10\x7a\x46\x05\xde\x0a\x5b\x37\xcb\x21\x17\x59\xc6\x81\x2b\xfe\x2e\x08\xff\xf0\x10\x24\x4b\x79\
@@ -51,7 +87,7 @@ mod tests {
 \x83\xdf\xe3\x0d\x2e\xf9\x0c\x8e\x2e\x2d\x68\x74\x74\x70\x73\x3a\x2f\x2f\x61\x6c\x69\x63\x65\x2e\x62\x74\x63\x2e\x63\x61\
 \x6c\x65\x6e\x64\x61\x72\x2e\x6f\x70\x65\x6e\x74\x69\x6d\x65\x73\x74\x61\x6d\x70\x73\x2e\x6f\x72\x67";
 
-    const LARGE_TEST: &'static [u8] = b"\
+    pub const LARGE_TEST: &[u8] = b"\
 \x00\x4f\x70\x65\x6e\x54\x69\x6d\x65\x73\x74\x61\x6d\x70\x73\x00\x00\x50\x72\x6f\x6f\x66\x00\xbf\x89\xe2\xe8\x84\xe8\x92\
 \x94\x01\x08\x6f\xd9\xc1\xc4\xf0\x96\xb7\x7e\x6d\x44\x57\xba\xc1\xc7\xf5\x10\x10\xd3\x18\xdb\x48\x3f\x28\x68\xd3\x79\x58\
 \x43\xf0\x98\xd3\x78\xf0\x10\xe2\xe2\x24\x43\x9e\x7f\x0f\xdd\x8c\x1e\xea\xc7\x3e\xa7\x39\xdb\x08\xf1\x20\xa5\x74\x44\x4a\
@@ -112,6 +148,114 @@ mod tests {
 \xed\x89\x57\x63\xf6\xad\xb3\x08\x08\xf1\x20\xe1\xc1\xae\xc4\x3e\x4c\xba\x0c\xc7\x6a\xed\xf0\x74\x33\xc2\x45\xaf\x3f\x8a\
 \xe2\xc0\x56\x45\xa1\x9c\x09\x09\x36\x4c\x3f\x30\x6e\x08\x08\x00\x05\x88\x96\x0d\x73\xd7\x19\x01\x03\xf5\xb6\x1b";
 
+    /// A pending timestamp using the less common RIPEMD160 and reverse ops
+    pub const RIPEMD_TEST: &[u8] = b"\
+\x00\x4f\x70\x65\x6e\x54\x69\x6d\x65\x73\x74\x61\x6d\x70\x73\x00\x00\x50\x72\x6f\x6f\x66\x00\xbf\x89\xe2\xe8\x84\xe8\x92\x94\
+\x01\x08\x37\x86\x72\x06\x36\x49\xae\x99\x9f\xb9\x4d\xc7\xbd\xb9\xca\x01\xab\x0d\x4f\xcc\x7e\xe2\xd0\x81\x24\xc8\xcd\x1a\x81\
+\xaf\x36\x79\xf0\x01\x01\x03\xf2\x08\x00\x83\xdf\xe3\x0d\x2e\xf9\x0c\x8e\x29\x28\x68\x74\x74\x70\x73\x3a\x2f\x2f\x66\x69\x6e\
+\x6e\x65\x79\x2e\x63\x61\x6c\x65\x6e\x64\x61\x72\x2e\x65\x74\x65\x72\x6e\x69\x74\x79\x77\x61\x6c\x6c\x2e\x63\x6f\x6d";
+
+    /// Small valid proofs covering every digest type, op and kind of
+    /// attestation, in linear, forked and nested shapes, with a name for each
+    pub fn corpus() -> Vec<(String, DetachedTimestampFile)> {
+        use attestation::Attestation;
+        use op::Op;
+        use ser::DigestType;
+        use timestamp::Timestamp;
+
+        let file = |digest_type: DigestType, paths: Vec<(Vec<Op>, Attestation)>| {
+            let digest = digest_type.hash(b"corpus");
+            let mut ots = DetachedTimestampFile::from_timestamp(digest_type, Timestamp::new(digest)).unwrap();
+            for (ops, attestation) in paths {
+                ots.attach_path(ops, attestation).unwrap();
+            }
+            ots
+        };
+        let nonce = || Op::Append(vec![0x5a; 16]);
+        let pending = || Attestation::pending("https://alice.btc.calendar.opentimestamps.org".to_owned()).unwrap();
+        let unknown = Attestation::Unknown { tag: vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08], data: b"future".to_vec() };
+
+        let mut corpus = vec![
+            ("small_test".to_owned(), DetachedTimestampFile::from_reader(SMALL_TEST).unwrap()),
+            ("large_test".to_owned(), DetachedTimestampFile::from_reader(LARGE_TEST).unwrap()),
+            ("ripemd_test".to_owned(), DetachedTimestampFile::from_reader(RIPEMD_TEST).unwrap()),
+        ];
+        for &digest_type in &[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160] {
+            corpus.push((format!("digest_{}", digest_type.name()), file(digest_type, vec![(vec![nonce(), Op::Sha256], pending())])));
+        }
+        let ops = vec![
+            Op::Sha1, Op::Sha256, Op::Ripemd160, Op::Hexlify, Op::Reverse,
+            Op::Append(vec![0xaa; 32]), Op::Prepend(vec![0xbb; 32]),
+        ];
+        for op in ops {
+            corpus.push((format!("op_{}", op.name()), file(DigestType::Sha256, vec![(vec![op, Op::Sha256], pending())])));
+        }
+        let attestations = vec![
+            ("bitcoin", Attestation::bitcoin(449397)),
+            ("litecoin", Attestation::litecoin(1234567)),
+            ("pending", pending()),
+            ("unknown", unknown.clone()),
+        ];
+        for (name, attestation) in attestations {
+            corpus.push((format!("attestation_{}", name), file(DigestType::Sha256, vec![(vec![Op::Sha256], attestation)])));
+        }
+        corpus.push(("forked".to_owned(), file(DigestType::Sha256, vec![
+            (vec![nonce(), Op::Sha256], pending()),
+            (vec![Op::Prepend(vec![0x01]), Op::Sha256], Attestation::bitcoin(1)),
+        ])));
+        corpus.push(("multi_attestation".to_owned(), file(DigestType::Sha256, vec![
+            (vec![], pending()),
+            (vec![], Attestation::bitcoin(1)),
+            (vec![], Attestation::litecoin(2)),
+            (vec![], unknown),
+        ])));
+
+        // A fork under the ops, where a calendar's continuation is merged
+        let mut nested = file(DigestType::Sha256, vec![(vec![nonce(), Op::Sha256], pending())]);
+        let commitment = nested.timestamp.commitment_for("https://alice.btc.calendar.opentimestamps.org").unwrap().to_vec();
+        let mut continuation = Timestamp::new(commitment.clone());
+        continuation.attach_path(&[Op::Prepend(vec![0x01; 4]), Op::Sha256], Attestation::bitcoin(3)).unwrap();
+        nested.timestamp.merge_continuation(&commitment, continuation).unwrap();
+        corpus.push(("nested_fork".to_owned(), nested));
+        corpus
+    }
+
+    /// Writes `corpus()` into `dir`, one `.ots` file per proof, e.g. to seed
+    /// a fuzzer, and returns the paths written
+    pub fn generate_corpus(dir: &Path) -> Result<Vec<PathBuf>, error::Error> {
+        fs::create_dir_all(dir)?;
+        let mut paths = vec![];
+        for (name, ots) in corpus() {
+            let path = dir.join(format!("{}.ots", name));
+            fs::write(&path, ots.to_bytes()?)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    #[test]
+    fn corpus_round_trip() {
+        let dir = temp_path::TempPath::new("corpus");
+        let paths = generate_corpus(&dir.0).unwrap();
+        assert_eq!(paths.len(), corpus().len());
+        for (path, (name, ots)) in paths.iter().zip(corpus()) {
+            let bytes = fs::read(path).unwrap();
+            let parsed = DetachedTimestampFile::from_reader(&bytes[..]).unwrap();
+            assert_eq!(parsed, ots, "{} did not round-trip", name);
+            assert_eq!(parsed.to_bytes().unwrap(), bytes);
+            parsed.timestamp.verify_outputs().unwrap();
+        }
+    }
+
+    /// Writes the corpus to `$OTS_CORPUS_DIR`, with
+    /// `OTS_CORPUS_DIR=<dir> cargo test write_corpus -- --ignored`
+    #[test]
+    #[ignore]
+    fn write_corpus() {
+        let dir = env::var("OTS_CORPUS_DIR").expect("OTS_CORPUS_DIR not set");
+        generate_corpus(Path::new(&dir)).unwrap();
+    }
+
     #[test]
     fn round_trip() {
         let mut rt1 = vec![];
@@ -128,5 +272,74 @@ mod tests {
         assert!(ots.unwrap().to_writer(&mut rt2).is_ok());
         assert_eq!(rt2, LARGE_TEST);
     }
+
+    #[test]
+    fn python_compatibility() {
+        // SMALL_TEST and LARGE_TEST were written by python-opentimestamps,
+        // LARGE_TEST after upgrading it from the calendars. Its file layout
+        // is ours: magic, version, digest type tag, digest, then the
+        // timestamp, with no separate format for files still pending.
+        for &file in &[SMALL_TEST, LARGE_TEST] {
+            let ots = DetachedTimestampFile::from_reader(file).unwrap();
+            assert_eq!(file[31], 0x01);
+            assert_eq!(file[32], ots.digest_type.to_tag());
+            assert_eq!(&file[33..65], &ots.timestamp.start_digest[..]);
+            assert_eq!(ots.to_bytes().unwrap(), file);
+            // It writes forks sorted, and for these files its order is
+            // also our canonical one
+            assert_eq!(ots.canonical_bytes().unwrap(), file);
+        }
+        let small = DetachedTimestampFile::from_reader(SMALL_TEST).unwrap();
+        assert_eq!(small.timestamp.count_pending(), 2);
+        let large = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        assert_eq!(large.timestamp.first_bitcoin_height(), Some(attestation::BlockHeight(449397)));
+    }
+
+    #[test]
+    fn ripemd160_proof() {
+        use bitcoin_hashes::{Hash, ripemd160, sha256};
+        use op::Op;
+        use timestamp::StepData;
+
+        let ots = DetachedTimestampFile::from_reader(RIPEMD_TEST).unwrap();
+        let mut rt = vec![];
+        ots.to_writer(&mut rt).unwrap();
+        assert_eq!(rt, RIPEMD_TEST);
+
+        let mut expected = ots.timestamp.start_digest.clone();
+        expected.push(0x01);
+        let mut step = &ots.timestamp.first_step;
+        assert_eq!(step.data, StepData::Op(Op::Append(vec![0x01])));
+        assert_eq!(step.output, expected);
+
+        step = &step.next[0];
+        expected = ripemd160::Hash::hash(&expected).to_byte_array().to_vec();
+        assert_eq!(step.data, StepData::Op(Op::Ripemd160));
+        assert_eq!(step.output, expected);
+
+        step = &step.next[0];
+        expected.reverse();
+        assert_eq!(step.data, StepData::Op(Op::Reverse));
+        assert_eq!(step.output, expected);
+
+        step = &step.next[0];
+        expected = sha256::Hash::hash(&expected).to_byte_array().to_vec();
+        assert_eq!(step.data, StepData::Op(Op::Sha256));
+        assert_eq!(step.output, expected);
+        assert_eq!(step.next[0].data, StepData::Attestation(attestation::Attestation::pending("https://finney.calendar.eternitywall.com".to_owned()).unwrap()));
+
+        ots.timestamp.verify_outputs().unwrap();
+        let display = ots.to_string();
+        assert!(display.contains("RIPEMD160()"));
+        assert!(display.contains("Reverse()"));
+    }
+
+    #[test]
+    fn truncated_op() {
+        // Cut the file off 50 bytes into the 174-byte transaction prefix
+        let start = LARGE_TEST.windows(3).position(|w| w == b"\xf1\xae\x01").unwrap();
+        let truncated = &LARGE_TEST[..start + 3 + 50];
+        assert_matches!(DetachedTimestampFile::from_reader(truncated), Err(error::Error::TruncatedOp { tag: 0xf1, expected: 174, got: 50 }));
+    }
 }
 
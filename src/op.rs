@@ -18,16 +18,40 @@
 //! timestamps.
 //!
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::{Read, Write};
+use std::sync::RwLock;
 
 use bitcoin_hashes::{Hash, ripemd160, sha1, sha256};
 use error::Error;
 use hex::Hexed;
 use ser;
 
-/// Maximum length of an op result
-const MAX_OP_LENGTH: usize = 4096;
+/// Maximum length of an op argument or result
+pub const MAX_OP_LENGTH: usize = 4096;
+
+/// Reads the argument of a binary op, reporting truncation in terms of the op
+fn read_arg<R: Read>(deser: &mut ser::Deserializer<R>, tag: u8) -> Result<Vec<u8>, Error> {
+    let max = deser.options().max_op_length;
+    deser.read_bytes(1, max).map_err(|e| match e {
+        Error::Truncated { expected, got } => Error::TruncatedOp { tag, expected, got },
+        e => e
+    })
+}
+
+/// A function executing a unary op that this crate doesn't know
+pub type Executor = fn(&[u8]) -> Vec<u8>;
+
+/// Executors for unary ops registered by downstream crates, by tag
+static EXECUTORS: RwLock<BTreeMap<u8, Executor>> = RwLock::new(BTreeMap::new());
+
+/// Looks up the executor registered for `tag`, if any
+fn executor(tag: u8) -> Option<Executor> {
+    // The map is never left half-updated, so a poisoned lock is still usable
+    let executors = EXECUTORS.read().unwrap_or_else(|e| e.into_inner());
+    executors.get(&tag).cloned()
+}
 
 /// All the types of operations supported
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -42,7 +66,10 @@ pub enum Op {
     Reverse,
     // binary ops
     Append(Vec<u8>),
-    Prepend(Vec<u8>)
+    Prepend(Vec<u8>),
+    /// A unary op with a tag we don't know, executed by a function registered
+    /// with `Op::register_executor`
+    Unknown(u8)
 }
 
 impl Op {
@@ -55,7 +82,89 @@ impl Op {
             Op::Hexlify => 0xf3,
             Op::Reverse => 0xf2,
             Op::Append(_) => 0xf0,
-            Op::Prepend(_) => 0xf1
+            Op::Prepend(_) => 0xf1,
+            Op::Unknown(tag) => tag
+        }
+    }
+
+    /// Returns a short lowercase name for the op, without its argument
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Op::Sha1 => "sha1",
+            Op::Sha256 => "sha256",
+            Op::Ripemd160 => "ripemd160",
+            Op::Hexlify => "hexlify",
+            Op::Reverse => "reverse",
+            Op::Append(_) => "append",
+            Op::Prepend(_) => "prepend",
+            Op::Unknown(_) => "unknown"
+        }
+    }
+
+    /// The name of an op defined by other OpenTimestamps implementations
+    /// but not supported here, for explaining why a timestamp using it can't
+    /// be parsed. Returns `None` for tags which are supported or unknown.
+    pub fn describe_tag(tag: u8) -> Option<&'static str> {
+        match tag {
+            // Supported by the Python and Java clients for Ethereum attestations
+            0x67 => Some("keccak256"),
+            _ => None
+        }
+    }
+
+    /// The ops computing HASH160, RIPEMD160 of SHA256, as used for Bitcoin
+    /// addresses. The OpenTimestamps format has no single op for this, so
+    /// it is built from the two standard ones.
+    pub fn hash160() -> Vec<Op> {
+        vec![Op::Sha256, Op::Ripemd160]
+    }
+
+    /// The length of the result of executing the op on `input_len` bytes,
+    /// if it can be known without executing it
+    pub fn output_len(&self, input_len: usize) -> Option<usize> {
+        Some(match *self {
+            Op::Sha1 | Op::Ripemd160 => 20,
+            Op::Sha256 => 32,
+            Op::Hexlify => 2 * input_len,
+            Op::Reverse => input_len,
+            Op::Append(ref data) | Op::Prepend(ref data) => input_len + data.len(),
+            Op::Unknown(_) => return None
+        })
+    }
+
+    /// Registers `f` as the executor of the unary op with the given tag, so
+    /// that ops with this tag are parsed as `Op::Unknown` rather than being
+    /// rejected. This is global, and lasts for the rest of the program;
+    /// registering a tag again replaces its executor. Tags which already mean
+    /// something, including the fork and attestation markers, are refused
+    /// with `Error::BadOpTag`.
+    pub fn register_executor(tag: u8, f: Executor) -> Result<(), Error> {
+        match tag {
+            0x00 | 0xff | 0x02 | 0x03 | 0x08 | 0xf0..=0xf3 => Err(Error::BadOpTag(tag)),
+            _ => {
+                let mut executors = EXECUTORS.write().unwrap_or_else(|e| e.into_inner());
+                executors.insert(tag, f);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether this is a cryptographic hash op which we consider secure.
+    /// SHA1 and RIPEMD160 are not; ops which aren't hashes at all also
+    /// return false.
+    pub fn is_secure_crypto(&self) -> bool {
+        match *self {
+            Op::Sha256 => true,
+            Op::Sha1 | Op::Ripemd160 => false,
+            Op::Hexlify | Op::Reverse | Op::Append(_) | Op::Prepend(_) | Op::Unknown(_) => false
+        }
+    }
+
+    /// The number of bytes the op serializes to
+    pub fn serialized_len(&self) -> usize {
+        match *self {
+            Op::Append(ref data) | Op::Prepend(ref data) => 1 + ser::uint_len(data.len()) + data.len(),
+            _ => 1
         }
     }
 
@@ -75,38 +184,78 @@ impl Op {
             0xf3 => Ok(Op::Hexlify),
             0xf2 => Ok(Op::Reverse),
             // binary ops are almost trivial
-            0xf0 => Ok(Op::Append(deser.read_bytes(1, MAX_OP_LENGTH)?)),
-            0xf1 => Ok(Op::Prepend(deser.read_bytes(1, MAX_OP_LENGTH)?)),
+            0xf0 => Ok(Op::Append(read_arg(deser, tag)?)),
+            0xf1 => Ok(Op::Prepend(read_arg(deser, tag)?)),
+            x if executor(x).is_some() => Ok(Op::Unknown(x)),
             x => Err(Error::BadOpTag(x))
         }
     }
 
-    /// Serialize the op into a serializer
+    /// Serialize the op into a serializer. Binary ops whose argument is
+    /// empty or longer than `MAX_OP_LENGTH` are an error, since the result
+    /// could not be parsed.
     pub fn serialize<W: Write>(&self, ser: &mut ser::Serializer<W>) -> Result<(), Error> {
-        ser.write_byte(self.tag())?;
-        if let Op::Append(ref data) = *self {
-            ser.write_bytes(data)?;
+        match *self {
+            Op::Append(ref data) | Op::Prepend(ref data) => {
+                if data.is_empty() || data.len() > MAX_OP_LENGTH {
+                    return Err(Error::BadLength { min: 1, max: MAX_OP_LENGTH, val: data.len() });
+                }
+                ser.write_byte(self.tag())?;
+                ser.write_bytes(data)
+            }
+            _ => ser.write_byte(self.tag())
+        }
+    }
+
+    /// Execute an op on the given data, failing rather than producing a
+    /// result longer than `max_len`, or with `Error::BadOpTag` rather than
+    /// panicking on an `Op::Unknown` with no registered executor
+    pub fn execute_checked(&self, input: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+        if let Op::Unknown(tag) = *self {
+            if executor(tag).is_none() {
+                return Err(Error::BadOpTag(tag));
+            }
         }
-        if let Op::Prepend(ref data) = *self {
-            ser.write_bytes(data)?;
+        // Refuse before executing where we can, to avoid allocating the result
+        if let Some(len) = self.output_len(input.len()) {
+            if len > max_len {
+                return Err(Error::OpOutputTooLong { op: self.name(), len });
+            }
+        }
+        let output = self.execute(input);
+        if output.len() > max_len {
+            return Err(Error::OpOutputTooLong { op: self.name(), len: output.len() });
         }
-        Ok(())
+        Ok(output)
     }
 
     /// Execute an op on the given data
+    ///
+    /// # Panics
+    ///
+    /// Panics on an `Op::Unknown` with no registered executor. Such ops are
+    /// never produced by parsing, so this can only happen for hand-built ops.
     pub fn execute(&self, input: &[u8]) -> Vec<u8> {
         match *self {
             Op::Sha1 => {
-                sha1::Hash::hash(&input).to_byte_array().to_vec()
+                sha1::Hash::hash(input).to_byte_array().to_vec()
             }
             Op::Sha256 => {
-                sha256::Hash::hash(&input).to_byte_array().to_vec()
+                sha256::Hash::hash(input).to_byte_array().to_vec()
             }
             Op::Ripemd160 => {
-                ripemd160::Hash::hash(&input).to_byte_array().to_vec()
+                ripemd160::Hash::hash(input).to_byte_array().to_vec()
             }
             Op::Hexlify => {
-                format!("{}", Hexed(input)).into_bytes()
+                // Lowercase, as python-opentimestamps does; written out by
+                // hand so the result can't depend on any formatting flags
+                const DIGITS: &[u8; 16] = b"0123456789abcdef";
+                let mut output = Vec::with_capacity(2 * input.len());
+                for byte in input {
+                    output.push(DIGITS[(byte >> 4) as usize]);
+                    output.push(DIGITS[(byte & 0x0f) as usize]);
+                }
+                output
             }
             Op::Reverse => {
                 input.iter().cloned().rev().collect()
@@ -121,7 +270,73 @@ impl Op {
                 vec.extend(input);
                 vec
             }
+            Op::Unknown(tag) => match executor(tag) {
+                Some(f) => f(input),
+                None => panic!("no executor registered for op tag 0x{:02x}", tag)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Op {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Op> {
+        // Binary ops need a non-empty argument no longer than MAX_OP_LENGTH
+        fn arg(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Vec<u8>> {
+            let len = u.int_in_range(1..=MAX_OP_LENGTH)?;
+            let mut arg = u.bytes(len.min(u.len()))?.to_vec();
+            if arg.is_empty() {
+                arg.push(0);
+            }
+            Ok(arg)
         }
+
+        Ok(match u.int_in_range(0..=6)? {
+            0 => Op::Sha1,
+            1 => Op::Sha256,
+            2 => Op::Ripemd160,
+            3 => Op::Hexlify,
+            4 => Op::Reverse,
+            5 => Op::Append(arg(u)?),
+            _ => Op::Prepend(arg(u)?)
+        })
+    }
+}
+
+/// A sequence of ops executed one after another, such as the path from a
+/// document to the commitment submitted to a calendar
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct OpChain(pub Vec<Op>);
+
+impl OpChain {
+    /// Executes every op in turn, starting from `input`. Fails like
+    /// `Op::execute_checked` if an op can't be executed or gives a result
+    /// longer than `MAX_OP_LENGTH`.
+    pub fn apply(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        self.0.iter().try_fold(input.to_vec(), |data, op| op.execute_checked(&data, MAX_OP_LENGTH))
+    }
+
+    /// Splits the chain into the ops before `index` and those from it on,
+    /// so that applying the first and then the second is the same as
+    /// applying the whole chain
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the length of the chain.
+    pub fn split_at(&self, index: usize) -> (OpChain, OpChain) {
+        let (first, second) = self.0.split_at(index);
+        (OpChain(first.to_vec()), OpChain(second.to_vec()))
+    }
+
+    /// Joins this chain and `other`, with `other`'s ops executed after ours
+    pub fn concat(&self, other: &OpChain) -> OpChain {
+        OpChain(self.0.iter().chain(&other.0).cloned().collect())
+    }
+}
+
+impl From<Vec<Op>> for OpChain {
+    fn from(ops: Vec<Op>) -> OpChain {
+        OpChain(ops)
     }
 }
 
@@ -130,13 +345,127 @@ impl fmt::Display for Op {
         match *self {
             Op::Sha1 => f.write_str("SHA1()"),
             Op::Sha256 => f.write_str("SHA256()"),
-            Op::Ripemd160 => f.write_str("RIPEMD16()"),
+            Op::Ripemd160 => f.write_str("RIPEMD160()"),
             Op::Hexlify => f.write_str("Hexlify()"),
             Op::Reverse => f.write_str("Reverse()"),
             Op::Append(ref data) => write!(f, "Append({})", Hexed(data)),
-            Op::Prepend(ref data) => write!(f, "Prepend({})", Hexed(data))
+            Op::Prepend(ref data) => write!(f, "Prepend({})", Hexed(data)),
+            Op::Unknown(tag) => write!(f, "Unknown[0x{:02x}]()", tag)
         }
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexlify() {
+        assert_eq!(Op::Hexlify.execute(b""), b"");
+        assert_eq!(Op::Hexlify.execute(b"\x00\x09\x0a\xab\xff"), b"00090aabff");
+
+        // Pseudorandom inputs of every length up to 256, against hex worked
+        // out a byte at a time
+        let mut state = 0x1234_5678u32;
+        for len in 0..256 {
+            let input: Vec<u8> = (0..len).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            }).collect();
+            let mut expected = vec![];
+            for byte in &input {
+                for nibble in &[byte >> 4, byte & 0x0f] {
+                    expected.push(match *nibble {
+                        n @ 0..=9 => b'0' + n,
+                        n => b'a' + n - 10
+                    });
+                }
+            }
+            let output = Op::Hexlify.execute(&input);
+            assert_eq!(output, expected);
+            assert_eq!(output.len(), Op::Hexlify.output_len(len).unwrap());
+            // Hashing the hex gives the same digest however many times
+            assert_eq!(Op::Sha256.execute(&output), Op::Sha256.execute(&Op::Hexlify.execute(&input)));
+        }
+    }
+
+    #[test]
+    fn serialize_arg_length() {
+        for op in &[Op::Append(vec![]), Op::Prepend(vec![]), Op::Append(vec![0; MAX_OP_LENGTH + 1])] {
+            let mut ser = ser::Serializer::new(vec![]);
+            assert_matches!(op.serialize(&mut ser), Err(Error::BadLength { min: 1, max: MAX_OP_LENGTH, .. }));
+            assert!(ser.into_inner().is_empty());
+        }
+
+        // Every argument length that serializes also parses
+        for &len in &[1, MAX_OP_LENGTH] {
+            for op in &[Op::Append(vec![0xaa; len]), Op::Prepend(vec![0xaa; len])] {
+                let mut ser = ser::Serializer::new(vec![]);
+                op.serialize(&mut ser).unwrap();
+                let bytes = ser.into_inner();
+                let mut deser = ser::Deserializer::new(&bytes[..]);
+                assert_eq!(Op::deserialize(&mut deser).unwrap(), *op);
+            }
+        }
+    }
+
+    #[test]
+    fn is_secure_crypto() {
+        assert!(Op::Sha256.is_secure_crypto());
+        for op in &[Op::Sha1, Op::Ripemd160, Op::Hexlify, Op::Reverse, Op::Append(vec![1]), Op::Prepend(vec![1])] {
+            assert!(!op.is_secure_crypto(), "{} should not be secure", op);
+        }
+    }
+
+    #[test]
+    fn describe_tag() {
+        assert_eq!(Op::describe_tag(0x67), Some("keccak256"));
+        assert_eq!(Op::describe_tag(0x08), None);
+        assert_eq!(Op::describe_tag(0x43), None);
+
+        let bytes = [0x67];
+        assert_matches!(Op::deserialize(&mut ser::Deserializer::new(&bytes[..])), Err(e @ Error::BadOpTag(0x67)) if e.to_string() == "op 0x67 (keccak256) is not supported by this build");
+        assert_eq!(Error::BadOpTag(0x43).to_string(), "invalid op tag 0x43");
+    }
+
+    #[test]
+    fn hash160() {
+        // HASH160 of the empty string
+        let output = OpChain::from(Op::hash160()).apply(b"").unwrap();
+        assert_eq!(format!("{}", Hexed(&output)), "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb");
+
+        // There is no HASH160 op on the wire
+        for tag in &[[0x07], [0x09], [0x0a]] {
+            let mut deser = ser::Deserializer::new(&tag[..]);
+            assert_matches!(Op::deserialize(&mut deser), Err(Error::BadOpTag(t)) if t == tag[0]);
+        }
+    }
+
+    #[test]
+    fn op_chain() {
+        let chain = OpChain::from(vec![
+            Op::Append(vec![0x01; 16]),
+            Op::Sha256,
+            Op::Prepend(vec![0x02, 0x03]),
+            Op::Reverse,
+            Op::Ripemd160,
+        ]);
+        let input = b"document digest";
+        let output = chain.apply(input).unwrap();
+        assert_eq!(output, Op::Ripemd160.execute(&Op::Reverse.execute(&Op::Prepend(vec![0x02, 0x03]).execute(
+            &Op::Sha256.execute(&Op::Append(vec![0x01; 16]).execute(input))))));
+
+        for index in 0..=chain.0.len() {
+            let (mine, theirs) = chain.split_at(index);
+            assert_eq!(mine.0.len(), index);
+            assert_eq!(mine.concat(&theirs), chain);
+            assert_eq!(theirs.apply(&mine.apply(input).unwrap()).unwrap(), output);
+        }
+        assert_eq!(OpChain::default().apply(input).unwrap(), input);
+
+        // Ops which can't be executed are errors
+        assert_matches!(OpChain::from(vec![Op::Sha256, Op::Unknown(0x44)]).apply(input), Err(Error::BadOpTag(0x44)));
+        assert_matches!(OpChain::from(vec![Op::Append(vec![0; MAX_OP_LENGTH])]).apply(input), Err(Error::OpOutputTooLong { op: "append", .. }));
+    }
+}
@@ -13,19 +13,42 @@
 //!
 //! Supports deserialization and serialization of OTS info files
 //!
+//! The format is that of python-opentimestamps, the reference client, which
+//! uses the same layout for pending and complete files. Files it writes are
+//! read and written back byte for byte, and files written here can be read by
+//! it unless they use ops with registered executors, which it lacks. The
+//! other difference is in ordering: when writing a fork it sorts the branches
+//! by their first op or attestation, comparing arguments as plain byte
+//! strings, whereas `Timestamp::canonicalize` sorts by the whole serialized
+//! branch, so shorter arguments come first. The two orders agree on typical
+//! proofs, and either parses to the same timestamp.
+//!
 
-use std::fmt;
+use std::{fmt, io};
 use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use bitcoin_hashes::{Hash, HashEngine, ripemd160, sha1, sha256};
+use std::{fs, path::Path};
 
+use attestation::{self, Attestation};
 use error::Error;
-use hex::Hexed;
-use timestamp::Timestamp;
+use hex::{self, Hexed};
+use op::{self, Op};
+use timestamp::{self, Step, StepData, Timestamp};
 
 /// Magic bytes that every proof must start with
 const MAGIC: &[u8] = b"\x00OpenTimestamps\x00\x00Proof\x00\xbf\x89\xe2\xe8\x84\xe8\x92\x94";
 
-/// Major version of timestamp files we understand
-const VERSION: usize = 1;
+/// Major version of timestamp files we understand, and write
+pub const SUPPORTED_VERSION: usize = 1;
+
+/// Largest document digest we consider plausible
+const MAX_DIGEST_LEN: usize = 64;
+
+/// Most zero bytes tolerated after a proof with `allow_trailing_padding`
+pub const MAX_TRAILING_PADDING: usize = 16;
 
 /// Structure representing an info file
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -37,19 +60,110 @@ pub struct DetachedTimestampFile {
 }
 
 impl DetachedTimestampFile {
+    /// Wraps a timestamp into an info file, taking the document digest from
+    /// its start digest, which must be the right length for `digest_type`
+    /// or this fails with `Error::DigestTypeMismatch`
+    pub fn from_timestamp(digest_type: DigestType, timestamp: Timestamp) -> Result<DetachedTimestampFile, Error> {
+        let ots = DetachedTimestampFile {
+            digest_type,
+            timestamp
+        };
+        ots.check_digest_type()?;
+        Ok(ots)
+    }
+
+    /// Checks that the document digest is the right length for the digest
+    /// type. Changing `digest_type` can't turn a proof of one hash into a
+    /// proof of another, so a mismatch is refused rather than written out.
+    fn check_digest_type(&self) -> Result<(), Error> {
+        let expected = self.digest_type.digest_len();
+        let got = self.timestamp.start_digest.len();
+        if got != expected {
+            return Err(Error::DigestTypeMismatch { digest_type: self.digest_type.name(), expected, got });
+        }
+        Ok(())
+    }
+
+    /// Adds a path executing `ops` on the document digest and ending in
+    /// `attestation`; see `Timestamp::attach_path`. On a freshly created
+    /// file this gives it its first path.
+    pub fn attach_path(&mut self, ops: Vec<Op>, attestation: Attestation) -> Result<(), Error> {
+        self.check_digest_type()?;
+        self.timestamp.attach_path(&ops, attestation)
+    }
+
+    /// Checks that `data` hashes to the document digest of the file. With
+    /// `DigestPolicy::RefuseWeak`, files using a weak digest type fail with
+    /// `Error::WeakDigest`; otherwise they are accepted with a warning logged.
+    pub fn verify_commitment(&self, data: &[u8], policy: DigestPolicy) -> Result<(), Error> {
+        self.check_digest(self.digest_type.hash(data), policy)
+    }
+
+    /// Checks that the file at `path` hashes to the document digest, like
+    /// `verify_commitment` with `DigestPolicy::AllowWeak`. The file is
+    /// hashed as it is read, so it is never held in memory.
+    pub fn verify_file(&self, path: &Path) -> Result<(), Error> {
+        let digest = self.digest_type.hash_reader(fs::File::open(path)?)?;
+        self.check_digest(digest, DigestPolicy::AllowWeak)
+    }
+
+    /// Streaming counterpart of `verify_commitment_full`: checks that the
+    /// data read from `reader` hashes to the document digest, hashing it in
+    /// chunks so that large documents are never held in memory, then
+    /// recomputes every step of the timestamp. Weak digest types are accepted
+    /// with a warning, as with `verify_file`.
+    pub fn verify_reader<R: Read>(&self, reader: R) -> Result<(), Error> {
+        let digest = self.digest_type.hash_reader(reader)?;
+        self.check_digest(digest, DigestPolicy::AllowWeak)?;
+        self.timestamp.verify_outputs()
+    }
+
+    /// Checks an already computed `document_digest` against the document
+    /// digest of the file. If they don't match but the reverse of
+    /// `document_digest` does, the digest was probably stored in the wrong
+    /// byte order, which is reported as `Error::ReversedDigest` so the user
+    /// can fix it; this never succeeds on a reversed digest.
+    pub fn verify_commitment_either_endian(&self, document_digest: &[u8]) -> Result<(), Error> {
+        match self.check_digest(document_digest.to_vec(), DigestPolicy::AllowWeak) {
+            Err(Error::CommitmentMismatch { .. }) if document_digest.iter().rev().eq(self.timestamp.start_digest.iter()) => {
+                Err(Error::ReversedDigest)
+            }
+            x => x
+        }
+    }
+
+    /// Checks a digest of some data against the document digest
+    fn check_digest(&self, digest: Vec<u8>, policy: DigestPolicy) -> Result<(), Error> {
+        if !self.digest_type.is_secure() {
+            match policy {
+                DigestPolicy::RefuseWeak => return Err(Error::WeakDigest(self.digest_type.name())),
+                DigestPolicy::AllowWeak => warn!("timestamp uses weak {} digest", self.digest_type),
+            }
+        }
+        if digest != self.timestamp.start_digest {
+            return Err(Error::CommitmentMismatch { expected: self.timestamp.start_digest.clone(), got: digest });
+        }
+        Ok(())
+    }
+
+    /// Like `verify_commitment`, but also recomputes every step of the
+    /// timestamp, failing with `Error::OutputMismatch` at the first step
+    /// whose recorded output is wrong
+    pub fn verify_commitment_full(&self, data: &[u8], policy: DigestPolicy) -> Result<(), Error> {
+        self.verify_commitment(data, policy)?;
+        self.timestamp.verify_outputs()
+    }
+
     /// Deserialize a info file from a reader
     pub fn from_reader<R: Read>(reader: R) -> Result<DetachedTimestampFile, Error> {
-        trace!("Start deserializing timestampfile from reader.");
-        let mut deser = Deserializer::new(reader);
+        DetachedTimestampFile::from_reader_with_options(reader, ParseOptions::default())
+    }
 
-        deser.read_magic()?;
-        trace!("Magic ok.");
-        deser.read_version()?;
-        trace!("Version ok.");
-        let digest_type = DigestType::from_tag(deser.read_byte()?)?;
-        trace!("Digest type: {}", digest_type);
-        let digest = deser.read_fixed_bytes(digest_type.digest_len())?;
-        trace!("Digest: {}", Hexed(&digest));
+    /// Deserialize a info file from a reader, with custom limits on the input
+    pub fn from_reader_with_options<R: Read>(reader: R, options: ParseOptions) -> Result<DetachedTimestampFile, Error> {
+        trace!("Start deserializing timestampfile from reader.");
+        let mut deser = Deserializer::with_options(reader, options);
+        let (digest_type, digest) = read_header(&mut deser)?;
         let timestamp = Timestamp::deserialize(&mut deser, digest)?;
 
         deser.check_eof()?;
@@ -60,8 +174,113 @@ impl DetachedTimestampFile {
         })
     }
 
-    /// Serialize the file into a reader
-    pub fn to_writer<W: Write>(&self, writer: W) -> Result<(), Error> {
+    /// Deserialize an info file from a byte slice, parsing the branches of
+    /// forks in parallel. See `Timestamp::from_slice_parallel` for details.
+    #[cfg(feature = "rayon")]
+    pub fn from_slice_parallel(bytes: &[u8]) -> Result<DetachedTimestampFile, Error> {
+        let options = ParseOptions::default();
+        let mut deser = Deserializer::with_options(bytes, options);
+        let (digest_type, digest) = read_header(&mut deser)?;
+        let header_len = deser.bytes_read();
+        let timestamp = Timestamp::from_slice_parallel(&bytes[header_len..], digest, options)?;
+
+        Ok(DetachedTimestampFile {
+            digest_type,
+            timestamp,
+        })
+    }
+
+    /// Deserialize an info file from a hex string, as pasted from elsewhere.
+    /// See `hex::from_hex` for what is tolerated around the hex.
+    pub fn from_hex(s: &str) -> Result<DetachedTimestampFile, Error> {
+        DetachedTimestampFile::from_reader(&hex::from_hex(s)?[..])
+    }
+
+    /// Checks whether a reader looks like it holds an info file, by reading
+    /// just the magic bytes at its start. Note that these bytes are consumed
+    /// from the reader, whatever the answer; nothing after them is read.
+    /// Input too short to hold the magic is not an info file.
+    pub fn sniff<R: Read>(reader: R) -> Result<bool, Error> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        reader.take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+        Ok(magic == MAGIC)
+    }
+
+    /// Reads just the header of an info file: the digest type and the digest
+    /// of the document it timestamps. Nothing after the digest is read, so
+    /// this is cheap even for huge proofs, but says nothing about whether
+    /// the rest of the file is valid.
+    pub fn read_header<R: Read>(reader: R) -> Result<(DigestType, Vec<u8>), Error> {
+        read_header(&mut Deserializer::new(reader))
+    }
+
+    /// Reads an info file from a reader, returning only the attestations in
+    /// it. No ops are executed and no tree is built, so this needs far less
+    /// memory than `from_reader` on huge proofs, but it also means nothing
+    /// is known about what the attestations commit to.
+    pub fn scan_attestations<R: Read>(reader: R) -> Result<Vec<Attestation>, Error> {
+        let mut attestations = vec![];
+        DetachedTimestampFile::for_each_attestation(reader, |attest| attestations.push(attest.clone()))?;
+        Ok(attestations)
+    }
+
+    /// Reads an info file from a reader, calling `f` on each attestation as
+    /// soon as it is parsed. Like `scan_attestations`, but without even
+    /// collecting the attestations, so memory use doesn't grow with the size
+    /// of the proof. Attestations seen before an error are still passed on.
+    pub fn for_each_attestation<R: Read, F: FnMut(&Attestation)>(reader: R, f: F) -> Result<(), Error> {
+        let mut deser = Deserializer::new(reader);
+        read_header(&mut deser)?;
+        Timestamp::for_each_attestation(&mut deser, f)?;
+        deser.check_eof()
+    }
+
+    /// Deserialize an info file by memory-mapping the file at `path`, which
+    /// avoids copying large proofs into memory before parsing them
+    ///
+    /// # Safety
+    ///
+    /// The file is mapped directly into memory, so if it is modified or
+    /// truncated (by this or any other process) while being parsed, the
+    /// behaviour is undefined. This typically manifests as garbage data or
+    /// a `SIGBUS`. Only use this on files which are known not to change
+    /// underneath us.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn from_path_mmap<P: AsRef<Path>>(path: P) -> Result<DetachedTimestampFile, Error> {
+        let file = fs::File::open(path)?;
+        let map = memmap2::Mmap::map(&file)?;
+        DetachedTimestampFile::from_reader(&map[..])
+    }
+
+    /// Like `to_writer`, but with `DigestPolicy::RefuseWeak` nothing is
+    /// written if the document digest is of a weak type or the timestamp
+    /// uses a SHA1 or RIPEMD160 op, failing instead with
+    /// `Error::WeakDigest`. This lets a service guarantee that only strong
+    /// proofs leave it.
+    pub fn to_writer_policy<W: Write>(&self, writer: W, policy: DigestPolicy) -> Result<usize, Error> {
+        if policy == DigestPolicy::RefuseWeak {
+            if !self.digest_type.is_secure() {
+                return Err(Error::WeakDigest(self.digest_type.name()));
+            }
+            let mut weak_op = None;
+            self.timestamp.walk(|step, _| match step.data {
+                StepData::Op(ref op @ Op::Sha1) | StepData::Op(ref op @ Op::Ripemd160) => {
+                    weak_op = weak_op.or_else(|| Some(op.name()));
+                }
+                _ => {}
+            });
+            if let Some(name) = weak_op {
+                return Err(Error::WeakDigest(name));
+            }
+        }
+        self.to_writer(writer)
+    }
+
+    /// Serialize the file into a writer, returning the number of bytes
+    /// written. Fails with `Error::DigestTypeMismatch`, writing nothing, if
+    /// the document digest is the wrong length for `digest_type`.
+    pub fn to_writer<W: Write>(&self, writer: W) -> Result<usize, Error> {
+        self.check_digest_type()?;
         let mut ser = Serializer::new(writer);
         ser.write_magic()?;
         ser.write_version()?;
@@ -70,18 +289,236 @@ impl DetachedTimestampFile {
         // to copy the way that python-opentimestamps is structured, though it is
         // an abstraction violation
         ser.write_fixed_bytes(&self.timestamp.start_digest)?;
-        self.timestamp.serialize(&mut ser)
+        self.timestamp.serialize(&mut ser)?;
+        Ok(ser.bytes_written())
+    }
+
+    /// The number of bytes the file serializes to, worked out without
+    /// serializing it
+    pub fn serialized_len(&self) -> usize {
+        MAGIC.len()
+            + uint_len(SUPPORTED_VERSION)
+            + 1
+            + self.timestamp.start_digest.len()
+            + self.timestamp.serialized_len()
+    }
+
+    /// Serialize the file into the start of `buf`, without allocating,
+    /// returning the number of bytes written. If `buf` is shorter than
+    /// `serialized_len()` this fails with `Error::BufferTooSmall` before
+    /// writing anything.
+    pub fn serialize_into_slice(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let needed = self.serialized_len();
+        if buf.len() < needed {
+            return Err(Error::BufferTooSmall { needed, len: buf.len() });
+        }
+        self.to_writer(buf)
+    }
+
+    /// Serialize the file into a new byte vector
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![];
+        self.to_writer(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Serialize the file after canonicalizing its timestamp (see
+    /// `Timestamp::canonicalize`), so that semantically identical files
+    /// serialize identically. The timestamp is validated first.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.timestamp.validate()?;
+        let mut canonical = self.clone();
+        canonical.timestamp.canonicalize();
+        canonical.to_bytes()
+    }
+
+    /// Writes the canonical serialization of the file gzip-compressed, for
+    /// archiving proofs with bulky attestations. This is a storage format of
+    /// this library only, not the OpenTimestamps format: other software
+    /// can't read it, and `load_compressed` is needed to read it back.
+    #[cfg(feature = "flate2")]
+    pub fn store_compressed<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+        encoder.write_all(&self.canonical_bytes()?)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Reads a file written by `store_compressed`
+    #[cfg(feature = "flate2")]
+    pub fn load_compressed<R: Read>(reader: R) -> Result<DetachedTimestampFile, Error> {
+        DetachedTimestampFile::from_reader(flate2::read::GzDecoder::new(reader))
+    }
+
+    /// Reads the file at `path`, decompressing it first if it is gzipped,
+    /// as `.ots.gz` files in some archives are. Gzip data is recognized by
+    /// its magic bytes, which can't start a timestamp file, so plain files
+    /// are read as usual.
+    #[cfg(feature = "flate2")]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<DetachedTimestampFile, Error> {
+        use std::io::BufRead;
+
+        let mut reader = io::BufReader::new(fs::File::open(path)?);
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            DetachedTimestampFile::load_compressed(reader)
+        } else {
+            DetachedTimestampFile::from_reader(reader)
+        }
+    }
+
+    /// A plain text dump of the file for keeping in version control, with
+    /// forks in canonical order (see `Timestamp::canonicalize`) and one line
+    /// per op or attestation. Each branch of a fork starts with a `branch`
+    /// line and is indented under it, so upgrading a proof shows up in a
+    /// diff as just the lines of the new branch. Unlike `Display`, this
+    /// leaves out step outputs, which follow from the ops anyway.
+    pub fn to_canonical_text(&self) -> String {
+        let mut canonical = self.timestamp.clone();
+        canonical.canonicalize();
+        let mut text = format!("{} {}\n", self.digest_type.name(), Hexed(&canonical.start_digest));
+        canonical_text_step(&canonical.first_step, 0, &mut text);
+        text
+    }
+
+    /// A stable identifier for the proof, suitable for indexing: the SHA256
+    /// of its canonical serialization. Since it depends on canonicalization,
+    /// proofs differing only in fork order share an id, and the id of a given
+    /// proof will change if the canonicalization rules ever do.
+    pub fn proof_id(&self) -> Result<[u8; 32], Error> {
+        let bytes = self.canonical_bytes()?;
+        Ok(sha256::Hash::hash(&bytes).to_byte_array())
+    }
+}
+
+/// Reads the magic, version, digest type and digest at the start of an info file
+fn read_header<R: Read>(deser: &mut Deserializer<R>) -> Result<(DigestType, Vec<u8>), Error> {
+    deser.read_magic()?;
+    trace!("Magic ok.");
+    deser.read_version()?;
+    trace!("Version ok.");
+    let digest_type = DigestType::from_tag(deser.read_byte()?)?;
+    trace!("Digest type: {} ({})", digest_type, digest_type.tag_hex());
+    let len = check_digest_len(digest_type.to_tag(), digest_type.digest_len())?;
+    let digest = deser.read_fixed_bytes(len)?;
+    // An empty digest would be trivially "timestamped" by anything, and
+    // can only indicate corruption or a broken digest type
+    if digest.is_empty() {
+        return Err(Error::BadLength { min: 1, max: MAX_DIGEST_LEN, val: 0 });
+    }
+    trace!("Digest: {}", Hexed(&digest));
+    Ok((digest_type, digest))
+}
+
+/// Checks that the digest length reported for the digest type with tag
+/// `tag` is small enough to allocate for. Every real digest type passes;
+/// this guards against a broken one making us allocate without limit.
+fn check_digest_len(tag: u8, len: usize) -> Result<usize, Error> {
+    if len > MAX_DIGEST_LEN {
+        return Err(Error::BadDigestTag(tag));
+    }
+    Ok(len)
+}
+
+impl Default for DetachedTimestampFile {
+    /// An empty SHA256 timestamp file on an all-zeroes digest. Like the
+    /// default `Timestamp`, this is a seed for building a real timestamp and
+    /// cannot be serialized as-is.
+    fn default() -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp::default()
+        }
+    }
+}
+
+impl From<sha1::Hash> for DetachedTimestampFile {
+    /// An empty SHA1 timestamp file (see `Timestamp::new`) on the given digest
+    fn from(hash: sha1::Hash) -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Sha1,
+            timestamp: Timestamp::new(hash.to_byte_array().to_vec())
+        }
+    }
+}
+
+impl From<sha256::Hash> for DetachedTimestampFile {
+    /// An empty SHA256 timestamp file (see `Timestamp::new`) on the given digest
+    fn from(hash: sha256::Hash) -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp::new(hash.to_byte_array().to_vec())
+        }
+    }
+}
+
+impl From<ripemd160::Hash> for DetachedTimestampFile {
+    /// An empty RIPEMD160 timestamp file (see `Timestamp::new`) on the given digest
+    fn from(hash: ripemd160::Hash) -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Ripemd160,
+            timestamp: Timestamp::new(hash.to_byte_array().to_vec())
+        }
+    }
+}
+
+/// Appends the lines of `to_canonical_text` for a step and everything
+/// following it, indented by `indent` spaces
+fn canonical_text_step(step: &Step, indent: usize, text: &mut String) {
+    let line = match step.data {
+        StepData::Fork => {
+            for branch in &step.next {
+                text.push_str(&format!("{:indent$}branch\n", "", indent = indent));
+                canonical_text_step(branch, indent + 2, text);
+            }
+            return;
+        }
+        StepData::Op(Op::Append(ref data)) => format!("append {}", Hexed(data)),
+        StepData::Op(Op::Prepend(ref data)) => format!("prepend {}", Hexed(data)),
+        StepData::Op(Op::Unknown(tag)) => format!("unknown 0x{:02x}", tag),
+        StepData::Op(ref op) => op.name().to_owned(),
+        StepData::Attestation(Attestation::Blockchain { chain, height }) => format!("{} {}", chain.to_string().to_lowercase(), height),
+        StepData::Attestation(Attestation::Pending { ref uri }) => format!("pending {}", uri),
+        StepData::Attestation(Attestation::Unknown { ref tag, ref data }) => format!("attestation {} {}", Hexed(tag), Hexed(data)),
+        StepData::Truncated => "truncated".to_owned()
+    };
+    text.push_str(&format!("{:indent$}{}\n", "", line, indent = indent));
+    for next in &step.next {
+        canonical_text_step(next, indent, text);
     }
 }
 
 impl fmt::Display for DetachedTimestampFile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{} digest of some data.", self.digest_type)?;
-        writeln!(f, "{}", self.timestamp)
+        let digest = &self.timestamp.start_digest;
+        write!(f, "{} digest of some data: {}", self.digest_type, Hexed(digest))?;
+        if digest.len() != self.digest_type.digest_len() {
+            f.write_str(" (length mismatch!)")?;
+        }
+        writeln!(f)?;
+        match f.precision() {
+            Some(max_lines) => writeln!(f, "{:.*}", max_lines, self.timestamp),
+            None => writeln!(f, "{}", self.timestamp)
+        }
     }
 }
 
-/// Type of hash used to produce the document digest
+/// The first bytes of any gzip stream
+#[cfg(feature = "flate2")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Whether to accept document digests of types considered weak
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DigestPolicy {
+    /// Accept weak digest types, logging a warning
+    AllowWeak,
+    /// Refuse weak digest types with `Error::WeakDigest`
+    RefuseWeak
+}
+
+/// Type of hash used to produce the document digest. Each type is tagged
+/// with the same byte as the op computing the same hash (see `hash_op`),
+/// but the two are never confused: the digest type's tag is only ever read
+/// from the file header, and ops only from the timestamp after the digest.
 #[allow(missing_docs)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum DigestType {
@@ -101,6 +538,18 @@ impl DigestType {
         }
     }
 
+    /// Infers the digest type from the length of a digest. This only works
+    /// for SHA256: SHA1 and RIPEMD160 digests are both 20 bytes, so that
+    /// length gives `Error::AmbiguousDigestLen`, and the caller has to find
+    /// out the type some other way.
+    pub fn from_len(len: usize) -> Result<DigestType, Error> {
+        match len {
+            32 => Ok(DigestType::Sha256),
+            20 => Err(Error::AmbiguousDigestLen(len)),
+            _ => Err(Error::UnknownDigestLen(len))
+        }
+    }
+
     /// Serialize a digest type by its tag
     pub fn to_tag(self) -> u8 {
         match self {
@@ -110,6 +559,66 @@ impl DigestType {
         }
     }
 
+    /// The op computing the same hash, which has the same tag
+    pub fn hash_op(self) -> Op {
+        match self {
+            DigestType::Sha1 => Op::Sha1,
+            DigestType::Sha256 => Op::Sha256,
+            DigestType::Ripemd160 => Op::Ripemd160
+        }
+    }
+
+    /// The tag as hex, e.g. `0x08`, for logging
+    pub fn tag_hex(self) -> String {
+        format!("0x{:02x}", self.to_tag())
+    }
+
+    /// Whether the hash function is considered secure. SHA1 and RIPEMD160
+    /// are not, so proofs of documents hashed with them prove little.
+    pub fn is_secure(self) -> bool {
+        match self {
+            DigestType::Sha256 => true,
+            DigestType::Sha1 | DigestType::Ripemd160 => false
+        }
+    }
+
+    /// A short lowercase name for the hash function
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestType::Sha1 => "sha1",
+            DigestType::Sha256 => "sha256",
+            DigestType::Ripemd160 => "ripemd160"
+        }
+    }
+
+    /// Hashes `data` with this hash function
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestType::Sha1 => sha1::Hash::hash(data).to_byte_array().to_vec(),
+            DigestType::Sha256 => sha256::Hash::hash(data).to_byte_array().to_vec(),
+            DigestType::Ripemd160 => ripemd160::Hash::hash(data).to_byte_array().to_vec()
+        }
+    }
+
+    /// Hashes everything `reader` produces with this hash function
+    pub fn hash_reader<R: Read>(self, mut reader: R) -> Result<Vec<u8>, Error> {
+        fn digest<H: Hash, R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+            let mut engine = H::engine();
+            let mut buf = [0; 8192];
+            loop {
+                match reader.read(&mut buf)? {
+                    0 => return Ok(H::from_engine(engine)[..].to_vec()),
+                    n => engine.input(&buf[..n])
+                }
+            }
+        }
+        match self {
+            DigestType::Sha1 => digest::<sha1::Hash, R>(&mut reader),
+            DigestType::Sha256 => digest::<sha256::Hash, R>(&mut reader),
+            DigestType::Ripemd160 => digest::<ripemd160::Hash, R>(&mut reader)
+        }
+    }
+
     /// The length, in bytes, that a digest with this hash function will be
     pub fn digest_len(self) -> usize {
         match self {
@@ -120,6 +629,13 @@ impl DigestType {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for DigestType {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<DigestType> {
+        Ok(*u.choose(&[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160])?)
+    }
+}
+
 impl fmt::Display for DigestType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -133,16 +649,265 @@ impl fmt::Display for DigestType {
 
 // ** I/O stuff **
 
+/// A reader which fails with `io::ErrorKind::TimedOut`, surfacing from the
+/// parser as `Error::Timeout`, once a deadline has passed.
+///
+/// For generic readers this is best-effort: the deadline is only checked
+/// between reads, so a single read which blocks forever still blocks
+/// forever. Sockets wrapped with `from_tcp` also get a read timeout, so
+/// that no single read can block for longer than the whole allowance.
+pub struct TimeoutReader<R: Read> {
+    inner: R,
+    deadline: Instant
+}
+
+impl<R: Read> TimeoutReader<R> {
+    /// Wraps a reader, allowing `timeout` from now for all reads
+    pub fn new(inner: R, timeout: Duration) -> TimeoutReader<R> {
+        TimeoutReader {
+            inner,
+            deadline: Instant::now() + timeout
+        }
+    }
+
+    /// Extracts the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl TimeoutReader<TcpStream> {
+    /// Wraps a socket, allowing `timeout` from now for all reads
+    pub fn from_tcp(stream: TcpStream, timeout: Duration) -> io::Result<TimeoutReader<TcpStream>> {
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(TimeoutReader::new(stream, timeout))
+    }
+}
+
+impl<R: Read> Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+        }
+        // Socket read timeouts show up as `WouldBlock` on some platforms
+        self.inner.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, e),
+            _ => e
+        })
+    }
+}
+
+/// A reader over a byte slice which keeps track of its position, and can
+/// hand out sub-slices of its input instead of copying them
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> SliceReader<'a> {
+    /// Wraps a slice, starting at its beginning
+    pub fn new(data: &'a [u8]) -> SliceReader<'a> {
+        SliceReader { data, pos: 0 }
+    }
+
+    /// The offset of the next byte to be read
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The input which has not been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Reads the next `n` bytes as a sub-slice of the input. If fewer than
+    /// `n` are left, returns how many there are, and reads nothing.
+    pub fn read_slice(&mut self, n: usize) -> Result<&'a [u8], usize> {
+        let remaining = self.remaining();
+        if remaining.len() < n {
+            return Err(remaining.len());
+        }
+        self.pos += n;
+        Ok(&remaining[..n])
+    }
+}
+
+impl<'a> Read for SliceReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decodes an unsigned integer, pulling one byte at a time from `next_byte`.
+/// Along with `write_uint_with`, this holds the varint logic independently
+/// of any particular I/O backend.
+/// Encodings of values which don't fit in a `usize` are rejected with
+/// `Error::UintOverflow`.
+pub fn read_uint_with<F: FnMut() -> Result<u8, Error>>(mut next_byte: F) -> Result<usize, Error> {
+    let mut ret = 0;
+    let mut shift = 0;
+
+    loop {
+        // Bottom 7 bits are value bits
+        let byte = next_byte()?;
+        let bits = (byte & 0x7f) as usize;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return Err(Error::UintOverflow);
+        }
+        ret |= bits << shift;
+        // Top bit is a continue bit
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    Ok(ret)
+}
+
+/// Encodes an unsigned integer, handing one byte at a time to `write_byte`;
+/// the counterpart of `read_uint_with`
+pub fn write_uint_with<F: FnMut(u8) -> Result<(), Error>>(mut n: u64, mut write_byte: F) -> Result<(), Error> {
+    if n == 0 {
+        write_byte(0x00)
+    } else {
+        while n > 0 {
+            if n > 0x7f {
+                write_byte((n as u8) | 0x80)?;
+            } else {
+                write_byte(n as u8)?;
+            }
+            n >>= 7;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes an unsigned integer as it appears in info files
+pub fn encode_uint(n: u64) -> Vec<u8> {
+    let mut ret = vec![];
+    // Writing into a vector can't fail
+    let _ = write_uint_with(n, |byte| { ret.push(byte); Ok(()) });
+    ret
+}
+
+/// Decodes an unsigned integer from the start of `bytes`, returning its
+/// value and the number of bytes it took up. Encodings of values which
+/// don't fit in a `usize` (a `u64` on 64-bit platforms) are rejected with
+/// `Error::UintOverflow`.
+pub fn decode_uint(bytes: &[u8]) -> Result<(u64, usize), Error> {
+    let mut iter = bytes.iter();
+    let n = read_uint_with(|| iter.next().cloned().ok_or_else(|| Error::Io(io::ErrorKind::UnexpectedEof.into())))?;
+    Ok((n as u64, bytes.len() - iter.len()))
+}
+
+/// The number of bytes `n` takes up when encoded as an unsigned integer
+pub fn uint_len(mut n: usize) -> usize {
+    let mut len = 1;
+    while n > 0x7f {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Limits applied during deserialization, to protect against hostile input
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParseOptions {
+    /// Maximum depth of the timestamp tree
+    pub max_depth: usize,
+    /// Maximum length of the argument to an op
+    pub max_op_length: usize,
+    /// Maximum number of bytes to read in total, if any
+    pub max_size: Option<usize>,
+    /// Maximum number of attestations to read in total, if any
+    pub max_attestations: Option<usize>,
+    /// Maximum length of the payload of each attestation. Payloads claiming
+    /// to be longer are rejected before anything is allocated for them.
+    pub max_attestation_len: usize,
+    /// Rather than failing on paths deeper than `max_depth`, skip the rest
+    /// of them, marking where with `StepData::Truncated`. This is for
+    /// displaying malformed proofs; the result cannot be serialized.
+    pub truncate_deep: bool,
+    /// Lowest file version to accept
+    pub min_version: usize,
+    /// Highest file version to accept. Raising this lets files with a newer
+    /// version be read on the assumption that they remain compatible.
+    pub max_version: usize,
+    /// Accept up to `MAX_TRAILING_PADDING` zero bytes after the proof, as
+    /// some calendars append. Anything else after the proof is still an
+    /// error.
+    pub allow_trailing_padding: bool
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_depth: timestamp::RECURSION_LIMIT,
+            max_op_length: op::MAX_OP_LENGTH,
+            max_size: None,
+            max_attestations: None,
+            max_attestation_len: attestation::MAX_ATTESTATION_LEN,
+            truncate_deep: false,
+            min_version: SUPPORTED_VERSION,
+            max_version: SUPPORTED_VERSION,
+            allow_trailing_padding: false
+        }
+    }
+}
+
 /// Standard deserializer for OTS info files
 pub struct Deserializer<R: Read> {
-    reader: R
+    reader: R,
+    options: ParseOptions,
+    bytes_read: usize,
+    attestations_read: usize
 }
 
 impl<R: Read> Deserializer<R> {
     /// Constructs a new deserializer from a reader
     pub fn new(reader: R) -> Deserializer<R> {
+        Deserializer::with_options(reader, ParseOptions::default())
+    }
+
+    /// Constructs a new deserializer from a reader, with custom limits
+    pub fn with_options(reader: R, options: ParseOptions) -> Deserializer<R> {
         Deserializer {
             reader,
+            options,
+            bytes_read: 0,
+            attestations_read: 0
+        }
+    }
+
+    /// The limits this deserializer is enforcing
+    pub fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    /// The number of bytes read so far
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Accounts for `n` more bytes being read, checking the size limit
+    fn consume(&mut self, n: usize) -> Result<(), Error> {
+        self.bytes_read = self.bytes_read.saturating_add(n);
+        match self.options.max_size {
+            Some(max) if self.bytes_read > max => Err(Error::SizeLimit(max)),
+            _ => Ok(())
+        }
+    }
+
+    /// Accounts for an attestation being read, checking the attestation limit
+    pub fn count_attestation(&mut self) -> Result<(), Error> {
+        self.attestations_read += 1;
+        match self.options.max_attestations {
+            Some(max) if self.attestations_read > max => Err(Error::TooManyAttestations(max)),
+            _ => Ok(())
         }
     }
 
@@ -151,9 +916,18 @@ impl<R: Read> Deserializer<R> {
         self.reader
     }
 
-    /// Reads the magic bytes and checks that they are what we expect
+    /// Reads the magic bytes and checks that they are what we expect. Input
+    /// which ends before the first byte gives `Error::EmptyInput`.
     pub fn read_magic(&mut self) -> Result<(), Error> {
-        let recv_magic = self.read_fixed_bytes(MAGIC.len())?;
+        self.consume(MAGIC.len())?;
+        let mut recv_magic = Vec::with_capacity(MAGIC.len());
+        self.reader.by_ref().take(MAGIC.len() as u64).read_to_end(&mut recv_magic)?;
+        if recv_magic.is_empty() {
+            return Err(Error::EmptyInput);
+        }
+        if recv_magic.len() < MAGIC.len() {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
         if recv_magic == MAGIC {
             Ok(())
         } else {
@@ -161,19 +935,22 @@ impl<R: Read> Deserializer<R> {
         }
     }
 
-    /// Reads the version and checks that it is what we expect
+    /// Reads the version and checks that it is within the range allowed
+    /// by the parse options
     pub fn read_version(&mut self) -> Result<(), Error> {
         let recv_version = self.read_uint()?;
-        if recv_version == VERSION {
+        let supported = self.options.min_version..=self.options.max_version;
+        if supported.contains(&recv_version) {
             Ok(())
         } else {
-            Err(Error::BadVersion(recv_version))
+            Err(Error::BadVersion { got: recv_version, supported })
         }
     }
 
 
     /// Reads a single byte from the reader
     pub fn read_byte(&mut self) -> Result<u8, Error> {
+        self.consume(1)?;
         let mut byte = [0];
         self.reader.read_exact(&mut byte)?;
         Ok(byte[0])
@@ -184,53 +961,98 @@ impl<R: Read> Deserializer<R> {
 
     /// Deserializes an unsigned integer
     pub fn read_uint(&mut self) -> Result<usize, Error> {
-        let mut ret = 0;
-        let mut shift = 0;
-
-        loop {
-            // Bottom 7 bits are value bits
-            let byte = self.read_byte()?;
-            ret |= ((byte & 0x7f) as usize) << shift;
-            // Top bit is a continue bit
-            if byte & 0x80 == 0 {
-                break;
-            }
-            shift += 7;
-        }
-
-        Ok(ret)
+        read_uint_with(|| self.read_byte())
     }
 
     /// Deserializes a fixed number of bytes
     pub fn read_fixed_bytes(&mut self, n: usize) -> Result<Vec<u8>, Error> {
+        self.consume(n)?;
         let mut ret = vec![0; n];
         self.reader.read_exact(&mut ret)?;
         Ok(ret)
     }
 
-    /// Deserializes a variable number of bytes
+    /// Deserializes a variable number of bytes. If the input ends before all
+    /// the bytes are read, returns `Error::Truncated`.
     pub fn read_bytes(&mut self, min: usize, max: usize) -> Result<Vec<u8>, Error> {
         let n = self.read_uint()?;
         if n < min || n > max {
             return Err(Error::BadLength { min, max, val: n });
         }
-        self.read_fixed_bytes(n)
+        self.consume(n)?;
+        let mut ret = Vec::with_capacity(n);
+        let got = self.reader.by_ref().take(n as u64).read_to_end(&mut ret)?;
+        if got < n {
+            return Err(Error::Truncated { expected: n, got });
+        }
+        Ok(ret)
     }
 
-    /// Check that there is no trailing data
+    /// Check that there is no trailing data, other than zero padding if
+    /// the parse options allow it
     pub fn check_eof(&mut self) -> Result<(), Error> {
-        if self.reader.by_ref().bytes().next().is_none() {
-            Ok(())
-        } else {
-            Err(Error::TrailingBytes)
+        if self.options.allow_trailing_padding {
+            let mut trailing = Vec::with_capacity(MAX_TRAILING_PADDING + 1);
+            self.reader.by_ref().take(MAX_TRAILING_PADDING as u64 + 1).read_to_end(&mut trailing)?;
+            if trailing.len() <= MAX_TRAILING_PADDING && trailing.iter().all(|&b| b == 0) {
+                return Ok(());
+            }
+            return Err(Error::TrailingBytes);
+        }
+
+        // Like `bytes().next().is_none()`, anything but a clean end of input
+        // counts as trailing data, read errors included
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Ok(_) | Err(_) => return Err(Error::TrailingBytes)
+            }
         }
     }
 }
 
+impl<'a> Deserializer<SliceReader<'a>> {
+    /// Constructs a new deserializer reading directly from a slice
+    pub fn from_slice(data: &'a [u8]) -> Deserializer<SliceReader<'a>> {
+        Deserializer::new(SliceReader::new(data))
+    }
+
+    /// Constructs a new deserializer reading directly from a slice, with
+    /// custom limits
+    pub fn from_slice_with_options(data: &'a [u8], options: ParseOptions) -> Deserializer<SliceReader<'a>> {
+        Deserializer::with_options(SliceReader::new(data), options)
+    }
+
+    /// The offset in the slice of the next byte to be read
+    pub fn position(&self) -> usize {
+        self.reader.position()
+    }
+
+    /// Like `read_fixed_bytes`, but borrows the bytes from the input rather
+    /// than copying them. If the input ends first, returns
+    /// `Error::Truncated`.
+    pub fn read_fixed_slice(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        self.consume(n)?;
+        self.reader.read_slice(n).map_err(|got| Error::Truncated { expected: n, got })
+    }
+
+    /// Like `read_bytes`, but borrows the bytes from the input rather than
+    /// copying them
+    pub fn read_bytes_slice(&mut self, min: usize, max: usize) -> Result<&'a [u8], Error> {
+        let n = self.read_uint()?;
+        if n < min || n > max {
+            return Err(Error::BadLength { min, max, val: n });
+        }
+        self.read_fixed_slice(n)
+    }
+}
 
 /// Standard serializer for OTS info files
 pub struct Serializer<W: Write> {
-    writer: W
+    writer: W,
+    bytes_written: usize
 }
 
 impl<W: Write> Serializer<W> {
@@ -238,9 +1060,15 @@ impl<W: Write> Serializer<W> {
     pub fn new(writer: W) -> Serializer<W> {
         Serializer {
             writer,
+            bytes_written: 0
         }
     }
 
+    /// The number of bytes written so far
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
     /// Extracts the underlying writer from the serializer
     pub fn into_inner(self) -> W {
         self.writer
@@ -253,34 +1081,24 @@ impl<W: Write> Serializer<W> {
 
     /// Writes the major version
     pub fn write_version(&mut self) -> Result<(), Error> {
-        self.write_uint(VERSION)
+        self.write_uint(SUPPORTED_VERSION)
     }
 
     /// Writes a single byte to the writer
     pub fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
-        self.writer.write_all(&[byte]).map_err(Error::Io)
+        self.write_fixed_bytes(&[byte])
     }
 
     /// Write an unsigned integer
-    pub fn write_uint(&mut self, mut n: usize) -> Result<(), Error> {
-        if n == 0 {
-            self.write_byte(0x00)
-        } else {
-            while n > 0 {
-                if n > 0x7f {
-                    self.write_byte((n as u8) | 0x80)?;
-                } else {
-                    self.write_byte(n as u8)?;
-                }
-                n >>= 7;
-            }
-            Ok(())
-        }
+    pub fn write_uint(&mut self, n: usize) -> Result<(), Error> {
+        write_uint_with(n as u64, |byte| self.write_byte(byte))
     }
 
     /// Write a fixed number of bytes
     pub fn write_fixed_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
-        self.writer.write_all(data).map_err(Error::Io)
+        self.writer.write_all(data).map_err(Error::Io)?;
+        self.bytes_written += data.len();
+        Ok(())
     }
 
     /// Write a variable number of bytes
@@ -300,5 +1118,743 @@ mod tests {
         assert_eq!(DigestType::Sha256.digest_len(), 32);
         assert_eq!(DigestType::Ripemd160.digest_len(), 20);
     }
+
+    #[test]
+    fn uint_encoding() {
+        let vectors: &[(usize, &[u8])] = &[
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (0x3fff, &[0xff, 0x7f]),
+            (0x4000, &[0x80, 0x80, 0x01]),
+        ];
+        for &(n, bytes) in vectors {
+            let mut ser = Serializer::new(vec![]);
+            ser.write_uint(n).unwrap();
+            assert_eq!(ser.into_inner(), bytes);
+
+            let mut deser = Deserializer::new(bytes);
+            assert_eq!(deser.read_uint().unwrap(), n);
+        }
+
+        for n in (0..100_000).chain((0..64).map(|shift| 1usize << shift)) {
+            let mut encoded = vec![];
+            write_uint_with(n as u64, |byte| { encoded.push(byte); Ok(()) }).unwrap();
+
+            let mut ser = Serializer::new(vec![]);
+            ser.write_uint(n).unwrap();
+            assert_eq!(ser.into_inner(), encoded);
+
+            assert_eq!(uint_len(n), encoded.len());
+
+            let mut iter = encoded.iter();
+            assert_eq!(read_uint_with(|| Ok(*iter.next().unwrap())).unwrap(), n);
+            assert!(iter.next().is_none());
+
+            assert_eq!(encode_uint(n as u64), encoded);
+            assert_eq!(decode_uint(&encoded).unwrap(), (n as u64, encoded.len()));
+        }
+    }
+
+    #[test]
+    fn uint_slices() {
+        let vectors: &[(u64, &[u8])] = &[
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16384, &[0x80, 0x80, 0x01]),
+            (u64::MAX, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+        ];
+        for &(n, bytes) in vectors {
+            assert_eq!(encode_uint(n), bytes);
+            assert_eq!(decode_uint(bytes).unwrap(), (n, bytes.len()));
+
+            // Only the integer is consumed
+            let mut followed = bytes.to_vec();
+            followed.extend_from_slice(&[0x05, 0x06]);
+            assert_eq!(decode_uint(&followed).unwrap(), (n, bytes.len()));
+        }
+
+        for bytes in &[&[][..], &[0x80][..], &[0xff, 0xff][..]] {
+            assert_matches!(decode_uint(bytes), Err(ref e) if e.is_truncation());
+        }
+        for bytes in &[&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02][..], &[0x80; 11][..]] {
+            assert_matches!(decode_uint(bytes), Err(Error::UintOverflow));
+        }
+
+        // The same limit applies when reading from a stream
+        let mut long = vec![0x80; 10];
+        long.push(0x01);
+        assert_matches!(Deserializer::new(&long[..]).read_uint(), Err(Error::UintOverflow));
+    }
+
+    #[test]
+    fn default() {
+        let ots = DetachedTimestampFile::default();
+        assert_eq!(ots.digest_type, DigestType::Sha256);
+        assert_eq!(ots.timestamp.start_digest.len(), ots.digest_type.digest_len());
+        assert!(ots.timestamp.is_empty());
+        assert!(ots.to_writer(vec![]).is_err());
+    }
+
+    #[test]
+    fn parse_options() {
+        use tests::LARGE_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        let generous = ParseOptions { max_size: Some(LARGE_TEST.len()), ..Default::default() };
+        assert_eq!(DetachedTimestampFile::from_reader_with_options(LARGE_TEST, generous).unwrap(), ots);
+
+        let small = ParseOptions { max_size: Some(LARGE_TEST.len() - 1), ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(LARGE_TEST, small), Err(Error::SizeLimit(n)) if n == LARGE_TEST.len() - 1);
+
+        let shallow = ParseOptions { max_depth: 10, ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(LARGE_TEST, shallow), Err(Error::StackOverflow));
+
+        // The transaction prefix is the largest op argument in the file
+        let short_ops = ParseOptions { max_op_length: 100, ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(LARGE_TEST, short_ops), Err(Error::BadLength { max: 100, .. }));
+    }
+
+    #[test]
+    fn max_attestations() {
+        use tests::LARGE_TEST;
+
+        // A fork of ten identical pending attestations
+        let mut bytes = vec![];
+        {
+            let mut ser = Serializer::new(&mut bytes);
+            ser.write_magic().unwrap();
+            ser.write_version().unwrap();
+            ser.write_byte(DigestType::Sha256.to_tag()).unwrap();
+            ser.write_fixed_bytes(&[0; 32]).unwrap();
+            for i in 0..10 {
+                if i < 9 {
+                    ser.write_byte(0xff).unwrap();
+                }
+                ser.write_byte(0x00).unwrap();
+                Attestation::Pending { uri: "https://example.com".to_owned() }.serialize(&mut ser).unwrap();
+            }
+        }
+
+        let capped = ParseOptions { max_attestations: Some(9), ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(&bytes[..], capped), Err(Error::TooManyAttestations(9)));
+        let enough = ParseOptions { max_attestations: Some(10), ..Default::default() };
+        DetachedTimestampFile::from_reader_with_options(&bytes[..], enough).unwrap();
+
+        // LARGE_TEST has four
+        let capped = ParseOptions { max_attestations: Some(3), ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(LARGE_TEST, capped), Err(Error::TooManyAttestations(3)));
+    }
+
+    #[test]
+    fn version() {
+        use tests::SMALL_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(SMALL_TEST).unwrap();
+        assert_eq!(SMALL_TEST[MAGIC.len()] as usize, SUPPORTED_VERSION);
+
+        // Accepting newer versions doesn't change how version 1 files parse
+        let relaxed = ParseOptions { max_version: 2, ..Default::default() };
+        assert_eq!(DetachedTimestampFile::from_reader_with_options(SMALL_TEST, relaxed).unwrap(), ots);
+
+        let mut v2 = SMALL_TEST.to_vec();
+        v2[MAGIC.len()] = 2;
+        assert_matches!(DetachedTimestampFile::from_reader(&v2[..]), Err(Error::BadVersion { got: 2, supported }) if supported == (1..=1));
+        assert_eq!(DetachedTimestampFile::from_reader_with_options(&v2[..], relaxed).unwrap(), ots);
+
+        let strict = ParseOptions { min_version: 2, max_version: 2, ..Default::default() };
+        assert_matches!(DetachedTimestampFile::from_reader_with_options(SMALL_TEST, strict), Err(Error::BadVersion { got: 1, .. }));
+    }
+
+    #[test]
+    fn tag_hex() {
+        assert_eq!(DigestType::Sha1.tag_hex(), "0x02");
+        assert_eq!(DigestType::Sha256.tag_hex(), "0x08");
+        assert_eq!(DigestType::Ripemd160.tag_hex(), "0x03");
+    }
+
+    #[test]
+    fn shared_tags() {
+        for &digest_type in &[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160] {
+            let op = digest_type.hash_op();
+            assert_eq!(op.tag(), digest_type.to_tag());
+            assert_eq!(op.execute(b"abc"), digest_type.hash(b"abc"));
+        }
+
+        // A proof on a RIPEMD160 digest, which starts with the SHA256 tag,
+        // whose timestamp starts with a SHA256 op and then a RIPEMD160 op
+        let digest = [0x08; 20];
+        let mut ots = DetachedTimestampFile::from_timestamp(DigestType::Ripemd160, Timestamp::new(digest.to_vec())).unwrap();
+        ots.attach_path(vec![Op::Sha256, Op::Ripemd160], Attestation::bitcoin(1)).unwrap();
+        let bytes = ots.to_bytes().unwrap();
+        assert_eq!(bytes[MAGIC.len() + 1..MAGIC.len() + 24], [&[0x03][..], &digest[..], &[0x08, 0x03][..]].concat()[..]);
+        let parsed = DetachedTimestampFile::from_reader(&bytes[..]).unwrap();
+        assert_eq!(parsed, ots);
+        assert_eq!(parsed.timestamp.first_step.data, StepData::Op(Op::Sha256));
+        assert_eq!(parsed.timestamp.first_step.next[0].data, StepData::Op(Op::Ripemd160));
+
+        // And the other way round
+        let digest = [0x03; 32];
+        let mut ots = DetachedTimestampFile::from_timestamp(DigestType::Sha256, Timestamp::new(digest.to_vec())).unwrap();
+        ots.attach_path(vec![Op::Ripemd160, Op::Sha256], Attestation::bitcoin(1)).unwrap();
+        let bytes = ots.to_bytes().unwrap();
+        assert_eq!(bytes[MAGIC.len() + 1], 0x08);
+        assert_eq!(bytes[MAGIC.len() + 2 + 32..MAGIC.len() + 2 + 34], [0x03, 0x08]);
+        let parsed = DetachedTimestampFile::from_reader(&bytes[..]).unwrap();
+        assert_eq!(parsed, ots);
+        assert_eq!(parsed.digest_type, DigestType::Sha256);
+
+        // Op tags which aren't also digest types aren't accepted as one
+        for &tag in &[0xf0, 0xf1, 0xf2, 0xf3] {
+            let mut bad = bytes.clone();
+            bad[MAGIC.len() + 1] = tag;
+            assert_matches!(DetachedTimestampFile::from_reader(&bad[..]), Err(Error::BadDigestTag(t)) if t == tag);
+        }
+    }
+
+    #[test]
+    fn header_only() {
+        use tests::LARGE_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        let (digest_type, digest) = DetachedTimestampFile::read_header(LARGE_TEST).unwrap();
+        assert_eq!(digest_type, ots.digest_type);
+        assert_eq!(digest, ots.timestamp.start_digest);
+
+        // The rest of the file isn't looked at
+        let header_len = MAGIC.len() + 2 + digest.len();
+        assert_eq!(DetachedTimestampFile::read_header(&LARGE_TEST[..header_len]).unwrap(), (digest_type, digest));
+        assert!(DetachedTimestampFile::read_header(&LARGE_TEST[..header_len - 1]).is_err());
+    }
+
+    #[test]
+    fn trailing_padding() {
+        use tests::SMALL_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(SMALL_TEST).unwrap();
+        let lenient = ParseOptions { allow_trailing_padding: true, ..Default::default() };
+        let mut padded = SMALL_TEST.to_vec();
+        padded.extend_from_slice(&[0, 0, 0]);
+        assert_matches!(DetachedTimestampFile::from_reader(&padded[..]), Err(Error::TrailingBytes));
+        assert_eq!(DetachedTimestampFile::from_reader_with_options(&padded[..], lenient).unwrap(), ots);
+        assert_eq!(DetachedTimestampFile::from_reader_with_options(SMALL_TEST, lenient).unwrap(), ots);
+
+        // Only zeros, and not too many of them
+        let mut garbage = padded.clone();
+        garbage.push(1);
+        assert!(DetachedTimestampFile::from_reader_with_options(&garbage[..], lenient).is_err());
+        padded.resize(SMALL_TEST.len() + MAX_TRAILING_PADDING + 1, 0);
+        assert!(DetachedTimestampFile::from_reader_with_options(&padded[..], lenient).is_err());
+    }
+
+    #[test]
+    fn check_eof_interrupted() {
+        // Reads once interrupted, then gives `data`
+        struct Interrupting<'a> { interrupted: bool, data: &'a [u8] }
+        impl<'a> Read for Interrupting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if !self.interrupted {
+                    self.interrupted = true;
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+                }
+                self.data.read(buf)
+            }
+        }
+
+        Deserializer::new(Interrupting { interrupted: false, data: &[] }).check_eof().unwrap();
+        assert_matches!(Deserializer::new(Interrupting { interrupted: false, data: &[0] }).check_eof(), Err(Error::TrailingBytes));
+    }
+
+    #[test]
+    fn proof_id() {
+        use tests::{LARGE_TEST, SMALL_TEST};
+
+        let ots = DetachedTimestampFile::from_reader(SMALL_TEST).unwrap();
+        let mut reordered = ots.clone();
+        reordered.timestamp.first_step.next[0].next[0].next.reverse();
+        assert!(reordered != ots);
+        assert_eq!(reordered.proof_id().unwrap(), ots.proof_id().unwrap());
+        assert_eq!(reordered.canonical_bytes().unwrap(), ots.canonical_bytes().unwrap());
+
+        let large = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        assert!(large.proof_id().unwrap() != ots.proof_id().unwrap());
+
+        // Malformed trees are rejected rather than panicking while sorting
+        let mut malformed = ots.clone();
+        let fork = &mut malformed.timestamp.first_step.next[0].next[0];
+        let empty = Step { data: StepData::Fork, output: fork.output.clone(), next: vec![] };
+        fork.next.push(empty);
+        assert_matches!(malformed.proof_id(), Err(Error::BadBranchCount { step: "fork", count: 0 }));
+        assert!(malformed.canonical_bytes().is_err());
+        assert!(malformed.to_canonical_text().contains("branch"));
+    }
+
+    #[test]
+    fn from_hash() {
+        let hash = sha256::Hash::hash(b"Hello, world!");
+        let ots = DetachedTimestampFile::from(hash);
+        assert_eq!(ots.digest_type, DigestType::Sha256);
+        assert_eq!(ots.timestamp.start_digest, hash.to_byte_array().to_vec());
+        assert!(ots.timestamp.is_empty());
+
+        let ots: DetachedTimestampFile = sha1::Hash::hash(b"Hello, world!").into();
+        assert_eq!(ots.digest_type, DigestType::Sha1);
+        assert_eq!(ots.timestamp.start_digest.len(), DigestType::Sha1.digest_len());
+
+        let ots: DetachedTimestampFile = ripemd160::Hash::hash(b"Hello, world!").into();
+        assert_eq!(ots.digest_type, DigestType::Ripemd160);
+        assert_eq!(ots.timestamp.start_digest.len(), DigestType::Ripemd160.digest_len());
+    }
+
+    #[test]
+    fn scan_attestations() {
+        use timestamp::{Step, StepData};
+        use tests::{LARGE_TEST, SMALL_TEST};
+
+        fn collect(step: &Step, attestations: &mut Vec<Attestation>) {
+            if let StepData::Attestation(ref attest) = step.data {
+                attestations.push(attest.clone());
+            }
+            for next in &step.next {
+                collect(next, attestations);
+            }
+        }
+
+        for bytes in &[SMALL_TEST, LARGE_TEST] {
+            let ots = DetachedTimestampFile::from_reader(*bytes).unwrap();
+            let mut expected = vec![];
+            collect(&ots.timestamp.first_step, &mut expected);
+            assert_eq!(DetachedTimestampFile::scan_attestations(*bytes).unwrap(), expected);
+        }
+
+        let mut trailing = LARGE_TEST.to_vec();
+        trailing.push(0);
+        assert_matches!(DetachedTimestampFile::scan_attestations(&trailing[..]), Err(Error::TrailingBytes));
+    }
+
+    #[test]
+    fn for_each_attestation() {
+        use attestation::{BlockHeight, Chain};
+        use tests::LARGE_TEST;
+
+        let mut count = 0;
+        let mut heights = vec![];
+        DetachedTimestampFile::for_each_attestation(LARGE_TEST, |attest| {
+            count += 1;
+            if let Some((Chain::Bitcoin, height)) = attest.anchor() {
+                heights.push(height);
+            }
+        }).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(heights, vec![BlockHeight(449399), BlockHeight(449397)]);
+
+        // Attestations before a truncation are still seen
+        let mut count = 0;
+        let end = LARGE_TEST.len() - 5;
+        assert_matches!(DetachedTimestampFile::for_each_attestation(&LARGE_TEST[..end], |_| count += 1), Err(Error::Io(_)));
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn from_slice() {
+        use tests::SMALL_TEST;
+
+        let mut deser = Deserializer::from_slice(SMALL_TEST);
+        let (digest_type, digest) = read_header(&mut deser).unwrap();
+        let timestamp = Timestamp::deserialize(&mut deser, digest).unwrap();
+        deser.check_eof().unwrap();
+        assert_eq!(deser.position(), SMALL_TEST.len());
+        assert_eq!(DetachedTimestampFile { digest_type, timestamp }, DetachedTimestampFile::from_reader(SMALL_TEST).unwrap());
+
+        // Sub-slices point into the input
+        let mut deser = Deserializer::from_slice(SMALL_TEST);
+        let magic = deser.read_fixed_slice(MAGIC.len()).unwrap();
+        assert_eq!(magic, MAGIC);
+        assert_eq!(magic.as_ptr(), SMALL_TEST.as_ptr());
+        assert_eq!(deser.position(), MAGIC.len());
+        deser.read_version().unwrap();
+        assert_eq!(deser.read_byte().unwrap(), 0x08);
+        let digest = deser.read_fixed_slice(32).unwrap();
+        assert_eq!(digest.as_ptr(), SMALL_TEST[MAGIC.len() + 2..].as_ptr());
+
+        let mut deser = Deserializer::from_slice(&SMALL_TEST[..10]);
+        assert_matches!(deser.read_fixed_slice(20), Err(Error::Truncated { expected: 20, got: 10 }));
+
+        let mut deser = Deserializer::from_slice(b"\x03abcd");
+        assert_eq!(deser.read_bytes_slice(1, 3).unwrap(), b"abc");
+        assert_eq!(deser.position(), 4);
+    }
+
+    #[test]
+    fn serialized_len() {
+        use tests::{LARGE_TEST, SMALL_TEST};
+
+        for bytes in &[SMALL_TEST, LARGE_TEST] {
+            let ots = DetachedTimestampFile::from_reader(*bytes).unwrap();
+            assert_eq!(ots.serialized_len(), ots.to_bytes().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn serialize_into_slice() {
+        let ots = DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let len = ::tests::SMALL_TEST.len();
+
+        let mut buf = vec![0xaa; len + 10];
+        assert_eq!(ots.serialize_into_slice(&mut buf).unwrap(), len);
+        assert_eq!(&buf[..len], ::tests::SMALL_TEST);
+        assert_eq!(&buf[len..], &[0xaa; 10][..]);
+
+        let mut buf = vec![0xaa; len - 1];
+        assert_matches!(ots.serialize_into_slice(&mut buf), Err(Error::BufferTooSmall { needed, len: got }) if needed == len && got == len - 1);
+        assert!(buf.iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn to_writer_count() {
+        use tests::{LARGE_TEST, SMALL_TEST};
+
+        for bytes in &[SMALL_TEST, LARGE_TEST] {
+            let ots = DetachedTimestampFile::from_reader(*bytes).unwrap();
+            let mut written = vec![];
+            let count = ots.to_writer(&mut written).unwrap();
+            assert_eq!(count, ots.to_bytes().unwrap().len());
+            assert_eq!(count, bytes.len());
+            assert_eq!(written, *bytes);
+        }
+    }
+
+    #[test]
+    fn sniff() {
+        use tests::LARGE_TEST;
+
+        let mut reader = LARGE_TEST;
+        assert!(DetachedTimestampFile::sniff(&mut reader).unwrap());
+        assert_eq!(reader, &LARGE_TEST[MAGIC.len()..]);
+
+        let random: Vec<u8> = (0..64u32).map(|i| (i * 167 + 13) as u8).collect();
+        assert!(!DetachedTimestampFile::sniff(&random[..]).unwrap());
+        assert!(!DetachedTimestampFile::sniff(&LARGE_TEST[..MAGIC.len() - 1]).unwrap());
+        assert!(!DetachedTimestampFile::sniff(&[][..]).unwrap());
+    }
+
+    #[test]
+    fn from_timestamp() {
+        let ots = DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let rebuilt = DetachedTimestampFile::from_timestamp(DigestType::Sha256, ots.timestamp.clone()).unwrap();
+        assert_eq!(rebuilt, ots);
+
+        assert_matches!(DetachedTimestampFile::from_timestamp(DigestType::Sha1, ots.timestamp), Err(Error::DigestTypeMismatch { digest_type: "sha1", expected: 20, got: 32 }));
+    }
+
+    #[test]
+    fn timeout_reader() {
+        use std::thread;
+        use tests::LARGE_TEST;
+
+        /// Serves its first 40 bytes promptly, then a byte every 50ms
+        struct Stalling<'a>(&'a [u8], usize);
+        impl<'a> Read for Stalling<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.1 >= 40 {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                match self.0.split_first() {
+                    Some((&byte, rest)) if !buf.is_empty() => {
+                        buf[0] = byte;
+                        self.0 = rest;
+                        self.1 += 1;
+                        Ok(1)
+                    }
+                    _ => Ok(0)
+                }
+            }
+        }
+
+        let reader = TimeoutReader::new(Stalling(LARGE_TEST, 0), Duration::from_millis(200));
+        assert_matches!(DetachedTimestampFile::from_reader(reader), Err(Error::Timeout));
+
+        let reader = TimeoutReader::new(LARGE_TEST, Duration::from_secs(60));
+        assert!(DetachedTimestampFile::from_reader(reader).is_ok());
+    }
+
+    #[test]
+    fn weak_digests() {
+        assert!(DigestType::Sha256.is_secure());
+        assert!(!DigestType::Sha1.is_secure());
+        assert!(!DigestType::Ripemd160.is_secure());
+
+        for &digest_type in &[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160] {
+            let data = b"Hello, world!";
+            let ots = DetachedTimestampFile {
+                digest_type,
+                timestamp: Timestamp::new(digest_type.hash(data))
+            };
+            assert_eq!(ots.timestamp.start_digest.len(), digest_type.digest_len());
+
+            ots.verify_commitment(data, DigestPolicy::AllowWeak).unwrap();
+            let result = ots.verify_commitment(data, DigestPolicy::RefuseWeak);
+            if digest_type.is_secure() {
+                result.unwrap();
+            } else {
+                assert_matches!(result, Err(Error::WeakDigest(name)) if name == digest_type.name());
+            }
+            assert_matches!(ots.verify_commitment(b"Goodbye", DigestPolicy::AllowWeak), Err(Error::CommitmentMismatch { .. }));
+        }
+    }
+
+    #[test]
+    fn digest_type_from_len() {
+        assert_eq!(DigestType::from_len(32).unwrap(), DigestType::Sha256);
+        assert_matches!(DigestType::from_len(20), Err(Error::AmbiguousDigestLen(20)));
+        assert_matches!(DigestType::from_len(99), Err(Error::UnknownDigestLen(99)));
+    }
+
+    #[test]
+    fn from_hex() {
+        use tests::SMALL_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(SMALL_TEST).unwrap();
+        let pasted = format!("\n0x{}\n", Hexed(SMALL_TEST));
+        assert_eq!(DetachedTimestampFile::from_hex(&pasted).unwrap(), ots);
+    }
+
+    #[test]
+    fn digest_type_mismatch() {
+        // Relabelling a SHA1 proof as SHA256 doesn't make it one
+        let digest = DigestType::Sha1.hash(b"data");
+        let mut ots = DetachedTimestampFile::from_timestamp(DigestType::Sha1, Timestamp::new(digest.clone())).unwrap();
+        ots.attach_path(vec![Op::Sha256], Attestation::bitcoin(1)).unwrap();
+        assert!(ots.to_bytes().is_ok());
+
+        ots.digest_type = DigestType::Sha256;
+        let mut written = vec![];
+        assert_matches!(ots.to_writer(&mut written), Err(Error::DigestTypeMismatch { digest_type: "sha256", expected: 32, got: 20 }));
+        assert!(written.is_empty());
+        assert_matches!(DetachedTimestampFile::from_timestamp(DigestType::Sha256, Timestamp::new(digest)), Err(Error::DigestTypeMismatch { digest_type: "sha256", expected: 32, got: 20 }));
+    }
+
+    #[test]
+    fn verify_reader() {
+        // 4 MB of data, generated as it is read
+        let document = || io::repeat(0x61).take(4 << 20);
+        let digest = DigestType::Sha256.hash_reader(document()).unwrap();
+        let mut ots = DetachedTimestampFile::from_timestamp(DigestType::Sha256, Timestamp::new(digest)).unwrap();
+        ots.attach_path(vec![Op::Append(vec![0x01]), Op::Sha256], Attestation::bitcoin(1)).unwrap();
+        ots.verify_reader(document()).unwrap();
+
+        assert_matches!(ots.verify_reader(document().take((4 << 20) - 1)), Err(Error::CommitmentMismatch { .. }));
+
+        ots.timestamp.first_step.output[0] ^= 1;
+        assert_matches!(ots.verify_reader(document()), Err(Error::OutputMismatch { depth: 0, .. }));
+    }
+
+    #[test]
+    fn display_digest() {
+        let mut ots = DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let display = format!("{}", ots);
+        let first_line = display.lines().next().unwrap();
+        assert_eq!(first_line, "SHA256 digest of some data: a70dfe69c5a0d62816781abb6e1777854718624a0d194231adb14c32ee5438a4");
+
+        ots.digest_type = DigestType::Sha1;
+        let display = format!("{}", ots);
+        assert!(display.lines().next().unwrap().ends_with("ee5438a4 (length mismatch!)"));
+    }
+
+    #[test]
+    fn digest_len_nonzero() {
+        for digest_type in &[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160] {
+            assert!(digest_type.digest_len() > 0);
+            assert!(digest_type.digest_len() <= MAX_DIGEST_LEN);
+            assert_eq!(check_digest_len(digest_type.to_tag(), digest_type.digest_len()).unwrap(), digest_type.digest_len());
+        }
+        // A digest type claiming a huge length is refused
+        assert_matches!(check_digest_len(0x42, 1 << 30), Err(Error::BadDigestTag(0x42)));
+    }
+
+    #[test]
+    fn attach_path() {
+        let alice = "https://alice.example.com";
+        let bob = "https://bob.example.com";
+        let digest = DigestType::Sha256.hash(b"Hello, world!");
+        let mut ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp::new(digest.clone())
+        };
+        let nonce = vec![0x12; 16];
+        ots.attach_path(vec![Op::Append(nonce.clone()), Op::Sha256], Attestation::Pending { uri: alice.to_owned() }).unwrap();
+        assert!(ots.timestamp.is_linear());
+        let commitment = Op::Sha256.execute(&[&digest[..], &nonce[..]].concat());
+        assert_eq!(ots.timestamp.commitment_for(alice), Some(&commitment[..]));
+
+        let bytes = ots.to_bytes().unwrap();
+        assert_eq!(DetachedTimestampFile::from_reader(&bytes[..]).unwrap(), ots);
+
+        // A second path goes alongside the first
+        ots.attach_path(vec![Op::Prepend(nonce), Op::Sha256], Attestation::Pending { uri: bob.to_owned() }).unwrap();
+        assert_eq!(ots.timestamp.branch_count(), 2);
+        assert!(ots.timestamp.commitment_for(bob).is_some());
+        ots.timestamp.verify_outputs().unwrap();
+        let bytes = ots.to_bytes().unwrap();
+        assert_eq!(DetachedTimestampFile::from_reader(&bytes[..]).unwrap(), ots);
+
+        let mut wrong_len = DetachedTimestampFile {
+            digest_type: DigestType::Sha1,
+            timestamp: Timestamp::new(digest)
+        };
+        assert_matches!(wrong_len.attach_path(vec![], Attestation::bitcoin(1)), Err(Error::DigestTypeMismatch { digest_type: "sha1", expected: 20, got: 32 }));
+    }
+
+    #[test]
+    fn empty_input() {
+        use tests::SMALL_TEST;
+
+        assert_matches!(DetachedTimestampFile::from_reader(io::empty()), Err(Error::EmptyInput));
+        assert_eq!(Error::EmptyInput.to_string(), "empty input, expected a .ots file");
+
+        // Input which ends partway through the magic is truncated, not empty
+        assert_matches!(DetachedTimestampFile::from_reader(&SMALL_TEST[..5]), Err(ref e) if e.is_truncation());
+    }
+
+    #[test]
+    fn to_writer_policy() {
+        use tests::{LARGE_TEST, RIPEMD_TEST};
+
+        let strong = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        let mut bytes = vec![];
+        strong.to_writer_policy(&mut bytes, DigestPolicy::RefuseWeak).unwrap();
+        assert_eq!(bytes, LARGE_TEST);
+
+        let mut sha1 = DetachedTimestampFile::from_timestamp(DigestType::Sha1, Timestamp::new(DigestType::Sha1.hash(b"data"))).unwrap();
+        sha1.attach_path(vec![Op::Sha256], Attestation::bitcoin(1)).unwrap();
+        let mut bytes = vec![];
+        assert_matches!(sha1.to_writer_policy(&mut bytes, DigestPolicy::RefuseWeak), Err(Error::WeakDigest("sha1")));
+        assert!(bytes.is_empty());
+        sha1.to_writer_policy(&mut bytes, DigestPolicy::AllowWeak).unwrap();
+        assert_eq!(bytes, sha1.to_bytes().unwrap());
+
+        // A SHA256 document digest put through a RIPEMD160 op
+        let ripemd = DetachedTimestampFile::from_reader(RIPEMD_TEST).unwrap();
+        assert_matches!(ripemd.to_writer_policy(vec![], DigestPolicy::RefuseWeak), Err(Error::WeakDigest("ripemd160")));
+    }
+
+    #[test]
+    fn verify_commitment_either_endian() {
+        let digest = DigestType::Sha256.hash(b"Hello, world!");
+        let ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp::new(digest.clone())
+        };
+        ots.verify_commitment_either_endian(&digest).unwrap();
+
+        let mut reversed = digest.clone();
+        reversed.reverse();
+        assert_matches!(ots.verify_commitment_either_endian(&reversed), Err(Error::ReversedDigest));
+        assert_matches!(ots.verify_commitment_either_endian(&DigestType::Sha256.hash(b"Goodbye")), Err(Error::CommitmentMismatch { .. }));
+    }
+
+    #[test]
+    fn verify_file() {
+        use temp_path::TempPath;
+
+        let data = b"Hello, world!";
+        let ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp::new(DigestType::Sha256.hash(data))
+        };
+        let path = TempPath::with_contents("verify-file", data);
+        ots.verify_file(&path.0).unwrap();
+        fs::write(&path, b"Goodbye").unwrap();
+        assert_matches!(ots.verify_file(&path.0), Err(Error::CommitmentMismatch { .. }));
+        drop(path);
+        assert_matches!(ots.verify_file(&TempPath::new("verify-file").0), Err(Error::Io(_)));
+
+        // Hashing a reader agrees with hashing a slice, across buffer sizes
+        let long = vec![0x61; 20000];
+        for &digest_type in &[DigestType::Sha1, DigestType::Sha256, DigestType::Ripemd160] {
+            assert_eq!(digest_type.hash_reader(&long[..]).unwrap(), digest_type.hash(&long));
+        }
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn compressed() {
+        use attestation::Attestation;
+        use timestamp::Step;
+        use tests::LARGE_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        let mut stored = vec![];
+        ots.store_compressed(&mut stored).unwrap();
+        assert_eq!(DetachedTimestampFile::load_compressed(&stored[..]).unwrap().canonical_bytes().unwrap(), ots.canonical_bytes().unwrap());
+
+        // A repetitive unknown attestation compresses well
+        let digest = vec![0x77; 32];
+        let bulky = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp {
+                start_digest: digest.clone(),
+                first_step: Step::attestation(Attestation::Unknown { tag: vec![0x01; 8], data: vec![0xee; 4000] }, digest)
+            }
+        };
+        let mut stored = vec![];
+        bulky.store_compressed(&mut stored).unwrap();
+        assert!(stored.len() < bulky.serialized_len() / 10);
+        assert_eq!(DetachedTimestampFile::load_compressed(&stored[..]).unwrap(), bulky);
+
+        // The uncompressed format isn't accepted
+        assert!(DetachedTimestampFile::load_compressed(LARGE_TEST).is_err());
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn from_path() {
+        use flate2::write::GzEncoder;
+        use temp_path::TempPath;
+        use tests::LARGE_TEST;
+
+        let ots = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        let plain = TempPath::with_contents("from-path.ots", LARGE_TEST);
+        let gzipped = TempPath::new("from-path.ots.gz");
+        let mut encoder = GzEncoder::new(fs::File::create(&gzipped).unwrap(), flate2::Compression::default());
+        encoder.write_all(LARGE_TEST).unwrap();
+        encoder.finish().unwrap();
+
+        assert_eq!(DetachedTimestampFile::from_path(&plain).unwrap(), ots);
+        assert_eq!(DetachedTimestampFile::from_path(&gzipped).unwrap(), ots);
+    }
+
+    #[test]
+    fn canonical_text() {
+        let ots = DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let text = ots.to_canonical_text();
+        assert!(text.starts_with(&format!("sha256 {}\n", Hexed(&ots.timestamp.start_digest))));
+        assert!(text.lines().any(|line| line == "sha256"));
+        assert_eq!(text.lines().filter(|line| line.trim() == "branch").count(), 2);
+        assert_ne!(text, ots.to_string());
+
+        // Reordering a fork doesn't change the text
+        let mut reordered = ots.clone();
+        reordered.timestamp.first_step.next[0].next[0].next.reverse();
+        assert_ne!(reordered, ots);
+        assert_eq!(reordered.to_canonical_text(), text);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn from_path_mmap() {
+        use temp_path::TempPath;
+        use tests::LARGE_TEST;
+
+        let path = TempPath::with_contents("mmap.ots", LARGE_TEST);
+        let mapped = unsafe { DetachedTimestampFile::from_path_mmap(&path) };
+        drop(path);
+
+        let ots = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+        assert_eq!(mapped.unwrap(), ots);
+    }
 }
 
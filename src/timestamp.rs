@@ -12,17 +12,25 @@
 //! # Timestamp
 //!
 
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
 use std::io::{Read, Write};
 
-use attestation::Attestation;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use bitcoin_hashes::{sha256d, Hash};
+
+use attestation::{validate_uri, Attestation, BlockHeaderProvider, BlockHeight, BlockProvider, Chain};
+use calendar::{Allowlist, CalendarProvider, UpgradeReport};
 use error::Error;
 use hex::Hexed;
-use op::Op;
+use op::{self, Op};
 use ser;
 
-/// Anti-DoS
-const RECURSION_LIMIT: usize = 256;
+/// Anti-DoS: default limit on the depth of a timestamp tree
+pub const RECURSION_LIMIT: usize = 256;
 
 /// The actual contents of the execution step
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -32,7 +40,11 @@ pub enum StepData {
     /// This step executes some concrete operation
     Op(Op),
     /// This step asserts an attestation of the current state by some timestamp service
-    Attestation(Attestation)
+    Attestation(Attestation),
+    /// Marks where the rest of a path was skipped for exceeding the depth
+    /// limit, when parsing with `ParseOptions::truncate_deep`. A timestamp
+    /// containing this is incomplete and fails validation.
+    Truncated
 }
 
 /// An execution step in a timestamp verification
@@ -46,6 +58,383 @@ pub struct Step {
     pub next: Vec<Step>
 }
 
+impl Step {
+    /// Creates a step executing `op` on `input`, followed by `next`, which
+    /// must all continue from the op's output
+    pub fn op(op: Op, input: &[u8], next: Vec<Step>) -> Result<Step, Error> {
+        let output = op.execute_checked(input, ::op::MAX_OP_LENGTH)?;
+        for step in &next {
+            step.check_outputs(&output, &mut vec![])?;
+        }
+        Ok(Step {
+            data: StepData::Op(op),
+            output,
+            next
+        })
+    }
+
+    /// Creates a step attesting to `output`
+    pub fn attestation(attest: Attestation, output: Vec<u8>) -> Step {
+        Step {
+            data: StepData::Attestation(attest),
+            output,
+            next: vec![]
+        }
+    }
+
+    /// Creates a fork of `branches` on `input`. The input has to be given,
+    /// rather than taken from the branches, since a branch starting with a
+    /// hash op doesn't reveal what it was executed on.
+    pub fn fork(input: &[u8], branches: Vec<Step>) -> Result<Step, Error> {
+        if branches.len() < 2 {
+            return Err(Error::BadBranchCount { step: "fork", count: branches.len() });
+        }
+        for step in &branches {
+            step.check_outputs(input, &mut vec![])?;
+        }
+        Ok(Step {
+            data: StepData::Fork,
+            output: input.to_vec(),
+            next: branches
+        })
+    }
+
+    /// Builds a chain of steps executing each of `ops` in turn on `input`,
+    /// ending in a step with the given data. Fails if an op can't be
+    /// executed or gives a result too long to be parsed back.
+    fn chain(input: &[u8], ops: &[Op], last: StepData) -> Result<Step, Error> {
+        let mut outputs = vec![];
+        let mut digest = input.to_vec();
+        for op in ops {
+            digest = op.execute_checked(&digest, op::MAX_OP_LENGTH)?;
+            outputs.push(digest.clone());
+        }
+
+        let mut step = Step {
+            data: last,
+            output: digest,
+            next: vec![]
+        };
+        for (op, output) in ops.iter().zip(outputs).rev() {
+            step = Step {
+                data: StepData::Op(op.clone()),
+                output,
+                next: vec![step]
+            };
+        }
+        Ok(step)
+    }
+
+    /// Inserts `ops` before every attestation under this step. On failure
+    /// the step may be left partly extended.
+    fn extend(&mut self, ops: &[Op]) -> Result<(), Error> {
+        if let StepData::Attestation(_) = self.data {
+            *self = Step::chain(&self.output, ops, self.data.clone())?;
+        } else {
+            for next in &mut self.next {
+                next.extend(ops)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the structure of this step and everything following it
+    fn validate(&self) -> Result<(), Error> {
+        match self.data {
+            StepData::Fork => if self.next.len() < 2 {
+                return Err(Error::BadBranchCount { step: "fork", count: self.next.len() });
+            },
+            StepData::Op(_) => match self.next.len() {
+                0 => return Err(Error::DanglingOp),
+                1 => {}
+                n => return Err(Error::BadBranchCount { step: "op", count: n })
+            },
+            StepData::Attestation(_) => if !self.next.is_empty() {
+                return Err(Error::BadBranchCount { step: "attestation", count: self.next.len() });
+            },
+            StepData::Truncated => return Err(Error::StackOverflow)
+        }
+        for next in &self.next {
+            next.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that executing this step and everything following it on
+    /// `input` reproduces the recorded outputs. On the first divergence,
+    /// returns `Error::OutputMismatch` with its depth below this step and
+    /// logs the path to it. `path` holds the steps above.
+    fn check_outputs<'a>(&'a self, input: &[u8], path: &mut Vec<&'a StepData>) -> Result<(), Error> {
+        let expected = match self.data {
+            StepData::Op(ref op) => op.execute_checked(input, op::MAX_OP_LENGTH)?,
+            StepData::Fork | StepData::Attestation(_) | StepData::Truncated => input.to_vec()
+        };
+        path.push(&self.data);
+        if self.output != expected {
+            let labels: Vec<String> = path.iter().map(|data| match **data {
+                StepData::Fork => "fork".to_owned(),
+                StepData::Op(ref op) => op.to_string(),
+                StepData::Attestation(ref attest) => attest.to_string(),
+                StepData::Truncated => "truncated".to_owned()
+            }).collect();
+            warn!("step output mismatch at depth {}, path: {}", path.len() - 1, labels.join(" -> "));
+            return Err(Error::OutputMismatch { depth: path.len() - 1, expected, got: self.output.clone() });
+        }
+        for next in &self.next {
+            next.check_outputs(&self.output, path)?;
+        }
+        path.pop();
+        Ok(())
+    }
+
+    /// Replaces the first leaf under this step which holds `old` and whose
+    /// output `replacement` continues from. Leaves which hold `old` but don't
+    /// fit are recorded in `mismatch`.
+    fn replace_attestation(&mut self, old: &Attestation, replacement: &Step, mismatch: &mut Option<Error>) -> bool {
+        for i in 0..self.next.len() {
+            if self.next[i].data == StepData::Attestation(old.clone()) {
+                match replacement.check_outputs(&self.next[i].output, &mut vec![]) {
+                    Ok(()) => {
+                        // A fork directly under a fork would be merged into
+                        // it on re-parsing, so merge it now
+                        if self.data == StepData::Fork && replacement.data == StepData::Fork {
+                            self.next.splice(i..=i, replacement.next.iter().cloned());
+                        } else {
+                            self.next[i] = replacement.clone();
+                        }
+                        return true;
+                    }
+                    Err(e) => *mismatch = Some(e)
+                }
+            } else if self.next[i].replace_attestation(old, replacement, mismatch) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Adds `branches` alongside the first attestation under this step whose
+    /// output is `commitment`, returning whether there was one
+    fn attach(&mut self, commitment: &[u8], branches: &[Step]) -> bool {
+        for i in 0..self.next.len() {
+            let is_leaf = match self.next[i].data {
+                StepData::Attestation(_) => self.next[i].output == commitment,
+                _ => false
+            };
+            if !is_leaf {
+                if self.next[i].attach(commitment, branches) {
+                    return true;
+                }
+            } else if self.data == StepData::Fork {
+                // A fork directly under a fork would be merged into it on
+                // re-parsing, so join this one
+                self.next.splice(i + 1..i + 1, branches.iter().cloned());
+                return true;
+            } else {
+                let mut next = vec![self.next[i].clone()];
+                next.extend(branches.iter().cloned());
+                self.next[i] = Step {
+                    data: StepData::Fork,
+                    output: commitment.to_vec(),
+                    next
+                };
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Replaces the prefix `from` of every pending attestation URI under
+    /// this step with `to`, counting the attestations changed
+    fn rewrite_uri(&mut self, from: &str, to: &str, count: &mut usize) {
+        if let StepData::Attestation(Attestation::Pending { ref mut uri }) = self.data {
+            if let Some(rest) = uri.strip_prefix(from).map(str::to_owned) {
+                *uri = format!("{}{}", to, rest);
+                *count += 1;
+            }
+        }
+        for next in &mut self.next {
+            next.rewrite_uri(from, to, count);
+        }
+    }
+
+    /// Replaces every blockchain attestation under this step with `pending`
+    fn downgrade(&mut self, pending: &Attestation) {
+        if let StepData::Attestation(Attestation::Blockchain { .. }) = self.data {
+            self.data = StepData::Attestation(pending.clone());
+        }
+        for next in &mut self.next {
+            next.downgrade(pending);
+        }
+    }
+
+    /// Copies this step and everything under it, replacing each op with the
+    /// result of `f` on it and recomputing outputs from `input`
+    fn map_ops<F: FnMut(&Op) -> Op>(&self, input: &[u8], f: &mut F) -> Result<Step, Error> {
+        let (data, output) = match self.data {
+            StepData::Op(ref op) => {
+                let op = f(op);
+                let output = op.execute_checked(input, op::MAX_OP_LENGTH)?;
+                (StepData::Op(op), output)
+            }
+            ref data => (data.clone(), input.to_vec())
+        };
+        let next = self.next
+            .iter()
+            .map(|next| next.map_ops(&output, f))
+            .collect::<Result<Vec<Step>, Error>>()?;
+        Ok(Step { data, output, next })
+    }
+
+    /// Finds the Bitcoin attestations under this step, along with the last
+    /// transaction on the path to each. `input` is the input to this step
+    /// and `tx` the last transaction above it.
+    fn bitcoin_transactions<'a>(&'a self, input: &'a [u8], mut tx: Option<&'a [u8]>, found: &mut Vec<BitcoinLeaf<'a>>) {
+        // A transaction is hashed with double SHA256 like a Merkle node,
+        // but a node is two 32-byte hashes and a transaction never is
+        if self.data == StepData::Op(Op::Sha256) && input.len() != 64 {
+            if let Some(next) = self.next.first() {
+                if next.data == StepData::Op(Op::Sha256) {
+                    tx = Some(input);
+                }
+            }
+        }
+        if let StepData::Attestation(Attestation::Blockchain { chain: Chain::Bitcoin, height }) = self.data {
+            found.push((height, &self.output, tx));
+        }
+        for next in &self.next {
+            next.bitcoin_transactions(&self.output, tx, found);
+        }
+    }
+
+    /// Whether any attestation under this step satisfies `pred`. Truncated
+    /// paths might, so they count as satisfying it.
+    fn keeps<F: Fn(&Attestation) -> bool>(&self, pred: &F) -> bool {
+        match self.data {
+            StepData::Attestation(ref attest) => pred(attest),
+            StepData::Truncated => true,
+            StepData::Fork | StepData::Op(_) => self.next.iter().any(|next| next.keeps(pred))
+        }
+    }
+
+    /// Removes fork branches under this step with no attestation satisfying
+    /// `pred`, returning how many were removed. Forks left with one branch
+    /// are replaced by it, and forks directly under forks are merged into
+    /// them.
+    fn retain<F: Fn(&Attestation) -> bool>(&mut self, pred: &F) -> usize {
+        let mut removed = 0;
+        if self.data == StepData::Fork {
+            let before = self.next.len();
+            self.next.retain(|next| next.keeps(pred));
+            removed += before - self.next.len();
+        }
+        for next in &mut self.next {
+            removed += next.retain(pred);
+        }
+        if self.data == StepData::Fork {
+            self.merge_forks();
+            if self.next.len() == 1 {
+                *self = self.next.pop().unwrap();
+            }
+        }
+        removed
+    }
+
+    /// Merges the branches of forks directly under this fork into it
+    fn merge_forks(&mut self) {
+        let mut branches = vec![];
+        for branch in self.next.drain(..) {
+            match branch.data {
+                StepData::Fork => branches.extend(branch.next),
+                _ => branches.push(branch)
+            }
+        }
+        self.next = branches;
+    }
+
+    /// Removes fork branches identical to an earlier branch of the same fork
+    /// under this step, returning how many were removed. Forks are tidied
+    /// up as in `retain`.
+    fn deduplicate(&mut self) -> usize {
+        let mut removed = 0;
+        for next in &mut self.next {
+            removed += next.deduplicate();
+        }
+        if self.data == StepData::Fork {
+            self.merge_forks();
+            let mut branches: Vec<Step> = vec![];
+            for branch in self.next.drain(..) {
+                if branches.contains(&branch) {
+                    removed += 1;
+                } else {
+                    branches.push(branch);
+                }
+            }
+            self.next = branches;
+            if self.next.len() == 1 {
+                *self = self.next.pop().unwrap();
+            }
+        }
+        removed
+    }
+
+    /// Sorts the branches of every fork under this step by their serialization
+    fn canonicalize(&mut self) {
+        for next in &mut self.next {
+            next.canonicalize();
+        }
+        if self.data == StepData::Fork {
+            self.next.sort_by_cached_key(|step| {
+                let mut ser = ser::Serializer::new(vec![]);
+                // Serializing into a vector can't fail, and any structural
+                // problem will be caught when the whole tree is serialized
+                let _ = Timestamp::serialize_step_recurse(&mut ser, step);
+                ser.into_inner()
+            });
+        }
+    }
+
+    /// Compares two steps, treating attestations as equal if they have the
+    /// same wire encoding
+    fn eq_ignoring_unknown(&self, other: &Step) -> bool {
+        if self.output != other.output || self.next.len() != other.next.len() {
+            return false;
+        }
+        let data_eq = match (&self.data, &other.data) {
+            (StepData::Attestation(a), StepData::Attestation(b)) => {
+                a == b || match (a, b) {
+                    (Attestation::Unknown { .. }, _) | (_, Attestation::Unknown { .. }) => {
+                        a.to_bytes().is_ok() && a.to_bytes().ok() == b.to_bytes().ok()
+                    }
+                    _ => false
+                }
+            }
+            (a, b) => a == b
+        };
+        data_eq && self.next.iter().zip(&other.next).all(|(a, b)| a.eq_ignoring_unknown(b))
+    }
+
+    /// The number of bytes this step and everything following it serialize to
+    fn serialized_len(&self) -> usize {
+        let own_len = match self.data {
+            // A marker before every branch but the last
+            StepData::Fork => self.next.len().saturating_sub(1),
+            StepData::Op(ref op) => op.serialized_len(),
+            StepData::Attestation(ref attest) => 1 + attest.serialized_len(),
+            StepData::Truncated => 0
+        };
+        own_len + self.next.iter().map(Step::serialized_len).sum::<usize>()
+    }
+
+    /// Calls `f` on this step and every step following it, depth first
+    fn walk<'a, F: FnMut(&'a Step, usize)>(&'a self, depth: usize, f: &mut F) {
+        f(self, depth);
+        for next in &self.next {
+            next.walk(depth + 1, f);
+        }
+    }
+}
+
 /// Main structure representing a timestamp
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Timestamp {
@@ -56,10 +445,68 @@ pub struct Timestamp {
 }
 
 impl Timestamp {
+    /// Creates an empty timestamp on the given digest. An empty timestamp is
+    /// a seed for building a real one: its first step is a fork with no
+    /// branches, so it attests to nothing and will fail validation (and
+    /// therefore serialization) until attestations are added to it.
+    pub fn new(start_digest: Vec<u8>) -> Timestamp {
+        Timestamp {
+            first_step: Step {
+                data: StepData::Fork,
+                output: start_digest.clone(),
+                next: vec![]
+            },
+            start_digest,
+        }
+    }
+
+    /// Whether this is an empty timestamp, as created by `Timestamp::new`
+    pub fn is_empty(&self) -> bool {
+        self.first_step.data == StepData::Fork && self.first_step.next.is_empty()
+    }
+
+    /// Combines timestamps of the same digest into one which forks into each
+    /// of them. Branches which themselves start with a fork are flattened
+    /// into the new fork, as they would be on re-parsing. Fails with
+    /// `Error::CommitmentMismatch` if a branch is of a different digest, and
+    /// with `Error::BadBranchCount` if there are fewer than two branches.
+    pub fn fork(start_digest: Vec<u8>, branches: Vec<Timestamp>) -> Result<Timestamp, Error> {
+        let mut steps = vec![];
+        for branch in branches {
+            if branch.start_digest != start_digest {
+                return Err(Error::CommitmentMismatch { expected: start_digest, got: branch.start_digest });
+            }
+            match branch.first_step.data {
+                StepData::Fork => steps.extend(branch.first_step.next),
+                _ => steps.push(branch.first_step)
+            }
+        }
+        Ok(Timestamp {
+            first_step: Step::fork(&start_digest, steps)?,
+            start_digest
+        })
+    }
+
+    /// Whether this timestamp and `other` can be merged, for example with
+    /// `Timestamp::fork`, which is so if they are timestamps of the same
+    /// digest
+    pub fn can_merge(&self, other: &Timestamp) -> bool {
+        self.start_digest == other.start_digest
+    }
+
     /// Deserialize one step in a timestamp. 
     fn deserialize_step_recurse<R: Read>(deser: &mut ser::Deserializer<R>, input_digest: Vec<u8>, tag: Option<u8>, recursion_limit: usize) -> Result<Step, Error> {
         if recursion_limit == 0 {
-            return Err(Error::StackOverflow);
+            if !deser.options().truncate_deep {
+                return Err(Error::StackOverflow);
+            }
+            scan_step_from(deser, tag, |_| {})?;
+            trace!("[{:3}] Truncated.", recursion_limit);
+            return Ok(Step {
+                data: StepData::Truncated,
+                output: input_digest,
+                next: vec![]
+            });
         }
 
         // Read next tag if we weren't given one
@@ -102,7 +549,7 @@ impl Timestamp {
             tag => {
                 // parse tag
                 let op = Op::deserialize_with_tag(deser, tag)?;
-                let output_digest = op.execute(&input_digest);
+                let output_digest = op.execute_checked(&input_digest, deser.options().max_op_length)?;
                 trace!("[{:3}] Tag {} maps {} to {}.", recursion_limit, op, Hexed(&input_digest), Hexed(&output_digest));
                 // recurse
                 let next = vec![Timestamp::deserialize_step_recurse(deser, output_digest.clone(), None, recursion_limit - 1)?];
@@ -115,9 +562,121 @@ impl Timestamp {
         }
     }
 
-    /// Deserialize a timestamp
+    /// Deserialize one step from the start of `bytes`, returning it along
+    /// with the number of bytes it occupied
+    #[cfg(feature = "rayon")]
+    fn deserialize_step_parallel(bytes: &[u8], input_digest: Vec<u8>, recursion_limit: usize, options: ser::ParseOptions) -> Result<(Step, usize), Error> {
+        if recursion_limit == 0 {
+            if !options.truncate_deep {
+                return Err(Error::StackOverflow);
+            }
+            let step = Step {
+                data: StepData::Truncated,
+                output: input_digest,
+                next: vec![]
+            };
+            return Ok((step, step_len(bytes, options)?));
+        }
+
+        let mut deser = ser::Deserializer::with_options(bytes, options);
+        match deser.read_byte()? {
+            // Attestation
+            0x00 => {
+                let attest = Attestation::deserialize(&mut deser)?;
+                let step = Step {
+                    data: StepData::Attestation(attest),
+                    output: input_digest,
+                    next: vec![]
+                };
+                Ok((step, deser.bytes_read()))
+            }
+            // Fork: find where each branch begins and ends, then parse them
+            // all concurrently
+            0xff => {
+                let mut ranges = vec![];
+                let mut pos = 1;
+                let mut last_branch = false;
+                loop {
+                    let end = pos + step_len(&bytes[pos..], options)?;
+                    ranges.push(pos..end);
+                    pos = end;
+                    if last_branch {
+                        break;
+                    }
+                    if bytes.get(pos) == Some(&0xff) {
+                        pos += 1;
+                    } else {
+                        last_branch = true;
+                    }
+                }
+
+                let forks = ranges
+                    .into_par_iter()
+                    .map(|range| {
+                        Timestamp::deserialize_step_parallel(&bytes[range], input_digest.clone(), recursion_limit - 1, options)
+                            .map(|(step, _)| step)
+                    })
+                    .collect::<Result<Vec<Step>, Error>>()?;
+                let step = Step {
+                    data: StepData::Fork,
+                    output: input_digest,
+                    next: forks
+                };
+                Ok((step, pos))
+            }
+            // An actual tag
+            tag => {
+                let op = Op::deserialize_with_tag(&mut deser, tag)?;
+                let output_digest = op.execute_checked(&input_digest, options.max_op_length)?;
+                let pos = deser.bytes_read();
+                let (next, len) = Timestamp::deserialize_step_parallel(&bytes[pos..], output_digest.clone(), recursion_limit - 1, options)?;
+                let step = Step {
+                    data: StepData::Op(op),
+                    output: output_digest,
+                    next: vec![next]
+                };
+                Ok((step, pos + len))
+            }
+        }
+    }
+
+    /// Deserialize a timestamp from a byte slice which should contain
+    /// exactly one timestamp, parsing the branches of forks in parallel.
+    ///
+    /// The format is sequential, so before the branches of a fork can be
+    /// handed to different threads their boundaries must be found, by
+    /// scanning over them without executing any ops. Each step is therefore
+    /// scanned once for every fork it is nested under, making the total work
+    /// O(n * d) for a timestamp of n steps with forks nested d deep, versus
+    /// O(n) for `deserialize`. Since scanning is much cheaper than hashing,
+    /// this is still a win for large proofs with many wide forks.
+    #[cfg(feature = "rayon")]
+    pub fn from_slice_parallel(bytes: &[u8], digest: Vec<u8>, options: ser::ParseOptions) -> Result<Timestamp, Error> {
+        if let Some(max) = options.max_size {
+            if bytes.len() > max {
+                return Err(Error::SizeLimit(max));
+            }
+        }
+        // Each branch is parsed with its own count, so check the total first
+        if options.max_attestations.is_some() {
+            scan_step(&mut ser::Deserializer::with_options(bytes, options), |_| {})?;
+        }
+        let (first_step, len) = Timestamp::deserialize_step_parallel(bytes, digest.clone(), options.max_depth, options)?;
+        if len != bytes.len() {
+            return Err(Error::TrailingBytes);
+        }
+
+        Ok(Timestamp {
+            start_digest: digest,
+            first_step,
+        })
+    }
+
+    /// Deserialize a timestamp, limiting its depth according to the
+    /// deserializer's options
     pub fn deserialize<R: Read>(deser: &mut ser::Deserializer<R>, digest: Vec<u8>) -> Result<Timestamp, Error> {
-        let first_step = Timestamp::deserialize_step_recurse(deser, digest.clone(), None, RECURSION_LIMIT)?;
+        let max_depth = deser.options().max_depth;
+        let first_step = Timestamp::deserialize_step_recurse(deser, digest.clone(), None, max_depth)?;
 
         Ok(Timestamp {
             start_digest: digest,
@@ -127,74 +686,1874 @@ impl Timestamp {
 
     fn serialize_step_recurse<W: Write>(ser: &mut ser::Serializer<W>, step: &Step) -> Result<(), Error> {
         match step.data {
-            StepData::Fork => {
-                for i in 0..step.next.len() - 1 {
-                    ser.write_byte(0xff)?;
-                    Timestamp::serialize_step_recurse(ser, &step.next[i])?;
+            StepData::Fork => match step.next.split_last() {
+                Some((last, rest)) => {
+                    for next in rest {
+                        ser.write_byte(0xff)?;
+                        Timestamp::serialize_step_recurse(ser, next)?;
+                    }
+                    Timestamp::serialize_step_recurse(ser, last)
                 }
-                Timestamp::serialize_step_recurse(ser, &step.next[step.next.len() - 1])
-            }
-            StepData::Op(ref op) => {
-                op.serialize(ser)?;
-                Timestamp::serialize_step_recurse(ser, &step.next[0])
-            }
+                None => Err(Error::BadBranchCount { step: "fork", count: 0 })
+            },
+            StepData::Op(ref op) => match step.next.first() {
+                Some(next) => {
+                    op.serialize(ser)?;
+                    Timestamp::serialize_step_recurse(ser, next)
+                }
+                None => Err(Error::DanglingOp)
+            },
             StepData::Attestation(ref attest) => {
                 ser.write_byte(0x00)?;
                 attest.serialize(ser)
             }
+            StepData::Truncated => Err(Error::StackOverflow)
         }
     }
 
-    /// Serialize a timestamp
+    /// The number of bytes the timestamp serializes to, not including the
+    /// start digest, which is written as part of the file header
+    pub fn serialized_len(&self) -> usize {
+        self.first_step.serialized_len()
+    }
+
+    /// Reads a timestamp, returning only its attestations, in the order they
+    /// appear. See `DetachedTimestampFile::scan_attestations`.
+    pub fn scan_attestations<R: Read>(deser: &mut ser::Deserializer<R>) -> Result<Vec<Attestation>, Error> {
+        let mut attestations = vec![];
+        scan_step(deser, |attest| attestations.push(attest))?;
+        Ok(attestations)
+    }
+
+    /// Reads a timestamp, calling `f` on each of its attestations as it is
+    /// parsed. See `DetachedTimestampFile::for_each_attestation`.
+    pub fn for_each_attestation<R: Read, F: FnMut(&Attestation)>(deser: &mut ser::Deserializer<R>, mut f: F) -> Result<(), Error> {
+        scan_step(deser, |attest| f(&attest))
+    }
+
+    /// Serialize a timestamp. The timestamp is validated first, so that a
+    /// malformed tree produces an error rather than unparseable output.
     pub fn serialize<W: Write>(&self, ser: &mut ser::Serializer<W>) -> Result<(), Error> {
+        self.validate()?;
         Timestamp::serialize_step_recurse(ser, &self.first_step)
     }
-}
 
-fn fmt_recurse(step: &Step, f: &mut fmt::Formatter, depth: usize, first_line: bool) -> fmt::Result {
-    fn indent(f: &mut fmt::Formatter, depth: usize, first_line: bool) -> fmt::Result {
-        if depth == 0 {
-            return Ok(());
+    /// Reads a bare timestamp, without the file header, as calendars send
+    /// them. The digest the timestamp starts from has to be given, since
+    /// it isn't part of the serialization. Fails with
+    /// `Error::TrailingBytes` if anything follows the timestamp.
+    pub fn from_reader_with_digest<R: Read>(reader: R, start_digest: Vec<u8>) -> Result<Timestamp, Error> {
+        let mut deser = ser::Deserializer::new(reader);
+        let timestamp = Timestamp::deserialize(&mut deser, start_digest)?;
+        deser.check_eof()?;
+        Ok(timestamp)
+    }
+
+    /// Writes the timestamp bare, without the file header or start digest,
+    /// returning the number of bytes written
+    pub fn to_writer<W: Write>(&self, writer: W) -> Result<usize, Error> {
+        let mut ser = ser::Serializer::new(writer);
+        self.serialize(&mut ser)?;
+        Ok(ser.bytes_written())
+    }
+
+    /// Checks that the timestamp is structurally sound: every path must end
+    /// in an attestation, every op must be followed by exactly one step, and
+    /// every fork must have at least two branches. Timestamps which were
+    /// deserialized always pass; this is mainly useful for timestamps which
+    /// were built by hand.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.first_step.validate()
+    }
+
+    /// Recomputes every step of the timestamp from the start digest, checking
+    /// it against the recorded outputs. On the first divergence, returns
+    /// `Error::OutputMismatch` with the depth of the offending step (the
+    /// first step being at depth 0), and logs the path to it.
+    pub fn verify_outputs(&self) -> Result<(), Error> {
+        self.first_step.check_outputs(&self.start_digest, &mut vec![])
+    }
+
+    /// Puts the timestamp into canonical form, by sorting the branches of
+    /// every fork by their serialization. Timestamps which differ only in the
+    /// order of their forks, and are therefore semantically identical, are
+    /// equal after canonicalization. (python-opentimestamps similarly sorts
+    /// attestations and ops when serializing.)
+    pub fn canonicalize(&mut self) {
+        self.first_step.canonicalize();
+    }
+
+    /// Compares two timestamps, considering an `Unknown` attestation equal to
+    /// a recognized attestation with the same tag and payload. This allows
+    /// comparing proofs parsed by versions of this library which understand
+    /// different sets of attestations.
+    pub fn eq_ignoring_unknown(&self, other: &Timestamp) -> bool {
+        self.start_digest == other.start_digest
+            && self.first_step.eq_ignoring_unknown(&other.first_step)
+    }
+
+    /// Returns every attestation step in the timestamp, in serialization order
+    fn attestation_steps(&self) -> Vec<&Step> {
+        let mut leaves = vec![];
+        self.walk(|step, _| if let StepData::Attestation(_) = step.data {
+            leaves.push(step);
+        });
+        leaves
+    }
+
+    /// Calls `f` on every step of the timestamp along with its depth, the
+    /// first step being at depth 0. Steps are visited depth first, in
+    /// serialization order.
+    pub fn walk<'a, F: FnMut(&'a Step, usize)>(&'a self, mut f: F) {
+        self.first_step.walk(0, &mut f);
+    }
+
+    /// The tags of all the ops used in the timestamp. A verifier can check
+    /// these against the ops it supports before attempting verification.
+    pub fn op_tags(&self) -> BTreeSet<u8> {
+        let mut tags = BTreeSet::new();
+        self.walk(|step, _| if let StepData::Op(ref op) = step.data {
+            tags.insert(op.tag());
+        });
+        tags
+    }
+
+    /// The distinct tags of attestations in the timestamp which this crate
+    /// doesn't understand, in the order they first appear. A verifier can
+    /// warn that these anchors need newer software to check.
+    pub fn unknown_attestation_tags(&self) -> Vec<Vec<u8>> {
+        let mut tags: Vec<Vec<u8>> = vec![];
+        for step in self.attestation_steps() {
+            if let StepData::Attestation(Attestation::Unknown { ref tag, .. }) = step.data {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
         }
+        tags
+    }
 
-        for _ in 0..depth-1 {
-            f.write_str("    ")?;
+    /// Whether the timestamp is a single chain of ops with no forks
+    pub fn is_linear(&self) -> bool {
+        let mut linear = true;
+        self.walk(|step, _| if step.data == StepData::Fork {
+            linear = false;
+        });
+        linear
+    }
+
+    /// The number of paths through the timestamp, i.e. the number of steps
+    /// which end one, usually attestations
+    pub fn branch_count(&self) -> usize {
+        let mut count = 0;
+        self.walk(|step, _| if step.data != StepData::Fork && step.next.is_empty() {
+            count += 1;
+        });
+        count
+    }
+
+    /// Displays the timestamp as its `Display` implementation does, but
+    /// stopping after `max_lines` lines, so that huge proofs don't flood a
+    /// terminal. If anything was left out a final line says how many steps.
+    ///
+    /// The same limit can be set with a precision in a format string, for
+    /// example `format!("{:.100}", timestamp)`.
+    pub fn display_truncated(&self, max_lines: usize) -> String {
+        format!("{:.*}", max_lines, self)
+    }
+
+    /// Checks that `leaf` is committed to by this timestamp, for timestamps
+    /// of the root of a Merkle tree of many documents: `path` is the ops
+    /// taking the leaf to the root, typically alternating appends or
+    /// prepends of sibling hashes with hash ops. Returns the commitment the
+    /// path computes, which is the start digest, or fails with
+    /// `Error::CommitmentMismatch` if it isn't.
+    pub fn verify_leaf(&self, leaf: &[u8], path: &[Op]) -> Result<Vec<u8>, Error> {
+        let mut commitment = leaf.to_vec();
+        for op in path {
+            commitment = op.execute_checked(&commitment, op::MAX_OP_LENGTH)?;
         }
-        if first_line {
-            f.write_str("--->")?;
+        if commitment != self.start_digest {
+            return Err(Error::CommitmentMismatch { expected: self.start_digest.clone(), got: commitment });
+        }
+        Ok(commitment)
+    }
+
+    /// Splits the timestamp at the commitment that was submitted to the
+    /// calendars, i.e. after the linear chain of ops at its start (typically
+    /// a nonce and a hash). Returns that chain of client-side ops and the
+    /// remainder of the timestamp, which begins at the commitment.
+    pub fn split_at_commitment(&self) -> (Vec<Op>, Timestamp) {
+        let mut ops = vec![];
+        let mut commitment = &self.start_digest;
+        let mut step = &self.first_step;
+        while let StepData::Op(ref op) = step.data {
+            if step.next.len() != 1 {
+                break;
+            }
+            ops.push(op.clone());
+            commitment = &step.output;
+            step = &step.next[0];
+        }
+
+        (ops, Timestamp {
+            start_digest: commitment.clone(),
+            first_step: step.clone()
+        })
+    }
+
+    /// Appends a chain of ops to every path in the timestamp, inserting them
+    /// just before each attestation and recomputing the outputs. This is for
+    /// building nested commitments; the attestations will now attest to the
+    /// new outputs, so pending attestations need to be resubmitted.
+    ///
+    /// Blockchain attestations cannot be moved in this way, so if the
+    /// timestamp has any, this returns `Error::AlreadyAttested` and leaves
+    /// the timestamp unchanged. It is likewise unchanged if an op can't be
+    /// executed or gives a result longer than `op::MAX_OP_LENGTH`.
+    pub fn extend(&mut self, ops: &[Op]) -> Result<(), Error> {
+        if !self.anchors().is_empty() {
+            return Err(Error::AlreadyAttested);
+        }
+        let mut first_step = self.first_step.clone();
+        first_step.extend(ops)?;
+        self.first_step = first_step;
+        Ok(())
+    }
+
+    /// Adds a path to the timestamp which executes `ops` on the start digest
+    /// and ends in `attestation`, computing all the outputs. This is the way
+    /// to build up an empty timestamp, e.g. from a commitment path fetched
+    /// earlier; on a timestamp which already has paths, the new one is
+    /// added alongside them in a fork. If an op can't be executed or gives
+    /// a result longer than `op::MAX_OP_LENGTH`, this fails and leaves the
+    /// timestamp unchanged.
+    pub fn attach_path(&mut self, ops: &[Op], attestation: Attestation) -> Result<(), Error> {
+        let path = Step::chain(&self.start_digest, ops, StepData::Attestation(attestation))?;
+        if self.first_step.data == StepData::Fork {
+            self.first_step.next.push(path);
         } else {
-            f.write_str("    ")?;
+            let first = self.first_step.clone();
+            self.first_step = Step {
+                data: StepData::Fork,
+                output: self.start_digest.clone(),
+                next: vec![first, path]
+            };
+        }
+        if self.first_step.next.len() == 1 {
+            self.first_step = self.first_step.next.pop().unwrap();
         }
         Ok(())
     }
 
-    match step.data {
-        StepData::Fork => {
-            indent(f, depth, first_line)?;
-            writeln!(f, "(fork {} ways)", step.next.len())?;
-            for fork in &step.next {
-                fmt_recurse(fork, f, depth + 1, true)?;
-            }
+    /// Merges a calendar's continuation of `commitment` into the timestamp,
+    /// alongside the first attestation on that commitment, which is kept.
+    /// The continuation must be a timestamp of `commitment` itself, not of
+    /// the document digest; its outputs are checked.
+    ///
+    /// On failure the timestamp is left unchanged. Fails with
+    /// `Error::CommitmentMismatch` if the continuation doesn't start from
+    /// `commitment`, with any error from `continuation.validate()` if it is
+    /// malformed, with `Error::OutputMismatch` or an error executing an op
+    /// if its outputs don't follow from `commitment`, and with
+    /// `Error::CommitmentNotFound` if no attestation is on `commitment`.
+    pub fn merge_continuation(&mut self, commitment: &[u8], continuation: Timestamp) -> Result<(), Error> {
+        if continuation.start_digest != commitment {
+            return Err(Error::CommitmentMismatch { expected: commitment.to_vec(), got: continuation.start_digest });
+        }
+        continuation.validate()?;
+        continuation.first_step.check_outputs(commitment, &mut vec![])?;
+
+        let branches = match continuation.first_step.data {
+            StepData::Fork => continuation.first_step.next,
+            _ => vec![continuation.first_step]
+        };
+        let first_is_leaf = match self.first_step.data {
+            StepData::Attestation(_) => self.first_step.output == commitment,
+            _ => false
+        };
+        if first_is_leaf {
+            let mut next = vec![self.first_step.clone()];
+            next.extend(branches);
+            self.first_step = Step {
+                data: StepData::Fork,
+                output: commitment.to_vec(),
+                next
+            };
             Ok(())
+        } else if self.first_step.attach(commitment, &branches) {
+            Ok(())
+        } else {
+            Err(Error::CommitmentNotFound)
         }
-        StepData::Op(ref op) => {
-            indent(f, depth, first_line)?;
-            writeln!(f, "execute {}", op)?;
-            indent(f, depth, false)?;
-            writeln!(f, " result {}", Hexed(&step.output))?;
-            fmt_recurse(&step.next[0], f, depth, false)
+    }
+
+    /// Replaces the attestation `old` with `replacement`, typically to swap
+    /// a pending attestation for the continuation returned by its calendar.
+    /// The replacement must continue from the commitment `old` attests to:
+    /// its outputs are recomputed from that commitment and must all match.
+    ///
+    /// Only the first leaf holding `old` that the replacement fits is
+    /// replaced. Returns whether a replacement was made; if `old` was found
+    /// but the replacement didn't fit it, returns `Error::OutputMismatch`.
+    pub fn replace_attestation(&mut self, old: &Attestation, replacement: Step) -> Result<bool, Error> {
+        replacement.validate()?;
+        let mut mismatch = None;
+        if self.first_step.data == StepData::Attestation(old.clone()) {
+            match replacement.check_outputs(&self.first_step.output, &mut vec![]) {
+                Ok(()) => {
+                    self.first_step = replacement;
+                    return Ok(true);
+                }
+                Err(e) => mismatch = Some(e)
+            }
+        } else if self.first_step.replace_attestation(old, &replacement, &mut mismatch) {
+            return Ok(true);
         }
-        StepData::Attestation(ref attest) => {
-            indent(f, depth, first_line)?;
-            writeln!(f, "result attested by {}", attest)
+        match mismatch {
+            Some(e) => Err(e),
+            None => Ok(false)
         }
     }
-}
 
-impl fmt::Display for Timestamp {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Starting digest: {}", Hexed(&self.start_digest))?;
-        fmt_recurse(&self.first_step, f, 0, false)
+    /// Returns the commitment attested to by the pending attestation with the
+    /// given URI, i.e. the digest that was submitted to that calendar. This
+    /// is the output of the op chain leading to the attestation, not the
+    /// document digest.
+    pub fn commitment_for(&self, uri: &str) -> Option<&[u8]> {
+        self.attestation_steps()
+            .into_iter()
+            .find(|step| match step.data {
+                StepData::Attestation(Attestation::Pending { uri: ref pending_uri }) => pending_uri == uri,
+                _ => false
+            })
+            .map(|step| &step.output[..])
     }
-}
 
+    /// Returns the distinct commitments attested to, i.e. the outputs of
+    /// the attestation steps, in the order they first appear. Several
+    /// attestations on the same commitment contribute it only once.
+    pub fn commitments(&self) -> Vec<Vec<u8>> {
+        let mut seen = HashSet::new();
+        self.attestation_steps()
+            .into_iter()
+            .filter(|step| seen.insert(&step.output))
+            .map(|step| step.output.clone())
+            .collect()
+    }
+
+    /// Checks every Bitcoin attestation against a caller-supplied map from
+    /// block heights to Merkle roots, without needing any network access.
+    /// The roots must be in the byte order they appear in the block header,
+    /// which is the reverse of how block explorers usually display them.
+    ///
+    /// Returns the heights of the attestations which were verified; those
+    /// whose heights are not in the map are skipped. Any attestation which
+    /// does not match its root is an error: `Error::ReversedRoot` if the
+    /// root was given in display order by mistake, otherwise
+    /// `Error::MerkleRootMismatch`.
+    pub fn verify_bitcoin_with_roots(&self, roots: &HashMap<BlockHeight, [u8; 32]>) -> Result<Vec<BlockHeight>, Error> {
+        let mut verified = vec![];
+        for step in self.attestation_steps() {
+            if let StepData::Attestation(Attestation::Blockchain { chain: Chain::Bitcoin, height }) = step.data {
+                if let Some(root) = roots.get(&height) {
+                    if step.output[..] != root[..] {
+                        if step.output.iter().eq(root.iter().rev()) {
+                            return Err(Error::ReversedRoot { height });
+                        }
+                        return Err(Error::MerkleRootMismatch { height });
+                    }
+                    verified.push(height);
+                }
+            }
+        }
+        Ok(verified)
+    }
+
+    /// Replaces pending attestations with the timestamps their calendars now
+    /// have on the same commitments, as `replace_attestation` does.
+    /// Calendars not on `allowlist` are never contacted, and a calendar
+    /// which fails or gives a timestamp which doesn't fit just leaves its
+    /// attestation as it was; the report says which was which.
+    pub fn upgrade<C: CalendarProvider>(&mut self, calendar: &C, allowlist: &Allowlist) -> UpgradeReport {
+        let pending: Vec<(String, Vec<u8>)> = self.attestation_steps()
+            .into_iter()
+            .filter_map(|step| match step.data {
+                StepData::Attestation(Attestation::Pending { ref uri }) => Some((uri.clone(), step.output.clone())),
+                _ => None
+            })
+            .collect();
+
+        let mut report = UpgradeReport::default();
+        for (uri, commitment) in pending {
+            if !allowlist.allows(&uri) {
+                debug!("Not contacting calendar {}: not on the allowlist", uri);
+                report.skipped.push(uri);
+                continue;
+            }
+            let upgraded = calendar.timestamp(&uri, &commitment).and_then(|continuation| {
+                let old = Attestation::Pending { uri: uri.clone() };
+                self.replace_attestation(&old, continuation.first_step)
+            });
+            match upgraded {
+                Ok(true) => report.upgraded.push(uri),
+                Ok(false) => report.failed.push((uri, Error::CommitmentNotFound)),
+                Err(e) => report.failed.push((uri, e))
+            }
+        }
+        report
+    }
+
+    /// Checks every Bitcoin attestation against the Merkle root `provider`
+    /// gives for its block, as `verify_bitcoin_with_roots` does. Returns the
+    /// heights of the attestations, all of which were verified.
+    pub fn verify_bitcoin<P: BlockHeaderProvider>(&self, provider: &P) -> Result<Vec<BlockHeight>, Error> {
+        let mut roots = HashMap::new();
+        for (chain, height) in self.anchors() {
+            if chain == Chain::Bitcoin && !roots.contains_key(&height) {
+                roots.insert(height, provider.merkle_root(height)?);
+            }
+        }
+        self.verify_bitcoin_with_roots(&roots)
+    }
+
+    /// Verifies the Bitcoin attestations as `verify_bitcoin` does, then
+    /// returns the earliest time of their blocks: the data existed before
+    /// then. Returns `None` if there are no Bitcoin attestations. Block
+    /// times are set by miners and may be off by an hour or two, so this is
+    /// a bound to state with some slack, not an exact moment.
+    pub fn earliest_time<P: BlockHeaderProvider>(&self, provider: &P) -> Result<Option<u32>, Error> {
+        let mut times = vec![];
+        for height in self.verify_bitcoin(provider)? {
+            times.push(provider.block_time(height)?);
+        }
+        Ok(times.into_iter().min())
+    }
+
+    /// For each Bitcoin attestation, in serialization order, the transaction
+    /// which the timestamp's ops build on the way to it, if any. This is
+    /// the input to the last double SHA256 on the path which isn't a Merkle
+    /// tree node, normally the calendar's transaction committing to it.
+    pub fn bitcoin_transactions(&self) -> Vec<(BlockHeight, Option<Vec<u8>>)> {
+        let mut found = vec![];
+        self.first_step.bitcoin_transactions(&self.start_digest, None, &mut found);
+        found.into_iter()
+            .map(|(height, _, tx)| (height, tx.map(|tx| tx.to_vec())))
+            .collect()
+    }
+
+    /// Checks every Bitcoin attestation against the full block it names:
+    /// the transaction built by the timestamp's ops has to be in the block,
+    /// and the block's transactions have to hash to the Merkle root the
+    /// attestation is on. This is stricter than checking the root alone, as
+    /// `verify_bitcoin` does. Returns the heights of the attestations.
+    pub fn verify_bitcoin_tx(&self, provider: &dyn BlockProvider) -> Result<Vec<BlockHeight>, Error> {
+        let mut found = vec![];
+        self.first_step.bitcoin_transactions(&self.start_digest, None, &mut found);
+
+        let mut blocks = HashMap::new();
+        let mut verified = vec![];
+        for (height, root, tx) in found {
+            let tx = match tx {
+                Some(tx) => tx,
+                None => return Err(Error::MissingTransaction { height })
+            };
+            let &mut (ref transactions, block_root) = match blocks.entry(height) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let transactions = provider.transactions(height)?;
+                    let txids = transactions.iter()
+                        .map(|tx| sha256d::Hash::hash(tx).to_byte_array())
+                        .collect();
+                    let root = merkle_root(txids);
+                    entry.insert((transactions, root))
+                }
+            };
+            if !transactions.iter().any(|block_tx| &block_tx[..] == tx) {
+                return Err(Error::TxNotInBlock { height });
+            }
+            if block_root.as_ref().map(|r| &r[..]) != Some(root) {
+                return Err(Error::MerkleRootMismatch { height });
+            }
+            verified.push(height);
+        }
+        Ok(verified)
+    }
+
+    /// Returns every blockchain attestation in the timestamp as a chain and
+    /// block height, in serialization order. A verifier needs to check all of
+    /// these to be sure of the earliest time the timestamp proves.
+    pub fn anchors(&self) -> Vec<(Chain, BlockHeight)> {
+        self.attestation_steps()
+            .into_iter()
+            .filter_map(|step| match step.data {
+                StepData::Attestation(ref attest) => attest.anchor(),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// The number of pending attestations, counted without collecting them
+    pub fn count_pending(&self) -> usize {
+        let mut count = 0;
+        self.walk(|step, _| if let StepData::Attestation(Attestation::Pending { .. }) = step.data {
+            count += 1;
+        });
+        count
+    }
+
+    /// The number of blockchain attestations, counted without collecting them
+    pub fn count_complete(&self) -> usize {
+        let mut count = 0;
+        self.walk(|step, _| if let StepData::Attestation(Attestation::Blockchain { .. }) = step.data {
+            count += 1;
+        });
+        count
+    }
+
+    /// The fraction of attestations which are in a blockchain rather than
+    /// pending: 0.0 for a freshly stamped proof, 1.0 for one with every
+    /// branch anchored. Unknown attestations aren't counted either way, and
+    /// a timestamp with no attestations is 0.0.
+    pub fn completeness(&self) -> f32 {
+        let complete = self.count_complete();
+        let pending = self.count_pending();
+        if complete == 0 {
+            0.0
+        } else {
+            complete as f32 / (complete + pending) as f32
+        }
+    }
+
+    /// Removes the branches which lead only to pending attestations, since
+    /// calendars' promises are of no use once the timestamp is in a
+    /// blockchain. Returns how many branches were removed.
+    ///
+    /// To avoid discarding the only evidence, a timestamp without a
+    /// blockchain attestation is left unchanged with `Error::NotAttested`.
+    pub fn prune(&mut self) -> Result<usize, Error> {
+        if self.anchors().is_empty() {
+            return Err(Error::NotAttested);
+        }
+        Ok(self.first_step.retain(&|attest: &Attestation| !matches!(*attest, Attestation::Pending { .. })))
+    }
+
+    /// Removes the branches with no attestation satisfying `pred`, for
+    /// trimming a proof down to the attestations someone cares about, and
+    /// returns how many branches were removed. Forks are tidied up as in
+    /// `prune`, which is this with a predicate rejecting pending attestations.
+    ///
+    /// If no attestation satisfies `pred`, nothing would be left, so the
+    /// timestamp is left unchanged with `Error::NothingRetained`.
+    pub fn retain_attestations<F: Fn(&Attestation) -> bool>(&mut self, pred: F) -> Result<usize, Error> {
+        if !self.first_step.keeps(&pred) {
+            return Err(Error::NothingRetained);
+        }
+        Ok(self.first_step.retain(&pred))
+    }
+
+    /// Points pending attestations at a calendar's new location, replacing
+    /// the prefix `from` of their URIs with `to`, and returns how many were
+    /// changed. Giving a whole URI as `from` rewrites just that calendar.
+    ///
+    /// Every rewritten URI has to be acceptable in a pending attestation;
+    /// if any isn't, the timestamp is left unchanged and the error returned.
+    pub fn rewrite_uri(&mut self, from: &str, to: &str) -> Result<usize, Error> {
+        for step in self.attestation_steps() {
+            if let StepData::Attestation(Attestation::Pending { ref uri }) = step.data {
+                if let Some(rest) = uri.strip_prefix(from) {
+                    validate_uri(&format!("{}{}", to, rest))?;
+                }
+            }
+        }
+        let mut count = 0;
+        self.first_step.rewrite_uri(from, to, &mut count);
+        Ok(count)
+    }
+
+    /// Returns a copy of the timestamp with every op replaced by the result
+    /// of `f` on it, for example to zero out nonces before sharing a proof,
+    /// with all step outputs recomputed. Fails if a new op produces output
+    /// longer than `op::MAX_OP_LENGTH`.
+    ///
+    /// Attestations are kept as they are, so any under a changed op will no
+    /// longer verify: they attest to the old commitment, not the new one.
+    pub fn map_ops<F: FnMut(&Op) -> Op>(&self, mut f: F) -> Result<Timestamp, Error> {
+        Ok(Timestamp {
+            start_digest: self.start_digest.clone(),
+            first_step: self.first_step.map_ops(&self.start_digest, &mut f)?
+        })
+    }
+
+    /// Removes fork branches which are identical to another branch of the
+    /// same fork, such as those left by merging overlapping proofs, and
+    /// returns how many were removed. A duplicate branch proves nothing its
+    /// twin doesn't, so this only makes the proof smaller. Identical subtrees
+    /// in different forks are left alone.
+    pub fn deduplicate_subtrees(&mut self) -> usize {
+        self.first_step.deduplicate()
+    }
+
+    /// Returns a copy of the timestamp with every blockchain attestation
+    /// replaced by a pending attestation from the calendar at `uri`, keeping
+    /// all the ops. This is for test harnesses exercising the upgrade path:
+    /// the result proves strictly less than the original, since it only
+    /// records a calendar's promise where there was a block.
+    pub fn to_pending(&self, uri: &str) -> Timestamp {
+        let mut pending = self.clone();
+        pending.first_step.downgrade(&Attestation::Pending { uri: uri.to_owned() });
+        pending
+    }
+
+    /// The height of the earliest Bitcoin block attesting to the timestamp,
+    /// which gives the tightest upper bound on when it was made
+    pub fn first_bitcoin_height(&self) -> Option<BlockHeight> {
+        self.anchors()
+            .into_iter()
+            .filter(|&(chain, _)| chain == Chain::Bitcoin)
+            .map(|(_, height)| height)
+            .min()
+    }
+}
+
+/// A Bitcoin attestation's height and commitment, with the last transaction
+/// on the path to it
+type BitcoinLeaf<'a> = (BlockHeight, &'a [u8], Option<&'a [u8]>);
+
+/// The Merkle root of a block with the given txids, as Bitcoin computes it,
+/// or `None` for a block with no transactions
+fn merkle_root(mut hashes: Vec<[u8; 32]>) -> Option<[u8; 32]> {
+    if hashes.is_empty() {
+        return None;
+    }
+    while hashes.len() > 1 {
+        // An odd hash out is paired with itself
+        if hashes.len() % 2 == 1 {
+            let last = hashes[hashes.len() - 1];
+            hashes.push(last);
+        }
+        hashes = hashes.chunks(2)
+            .map(|pair| {
+                let mut node = [0; 64];
+                node[..32].copy_from_slice(&pair[0]);
+                node[32..].copy_from_slice(&pair[1]);
+                sha256d::Hash::hash(&node).to_byte_array()
+            })
+            .collect();
+    }
+    Some(hashes[0])
+}
+
+/// Reads past one step and everything following it, without executing any
+/// ops or building any structures, passing each attestation to `f`. Since
+/// every fork adds one path and every attestation ends one, this needs no
+/// recursion.
+fn scan_step<R: Read, F: FnMut(Attestation)>(deser: &mut ser::Deserializer<R>, f: F) -> Result<(), Error> {
+    scan_step_from(deser, None, f)
+}
+
+/// Like `scan_step`, but for a step whose tag may already have been read
+fn scan_step_from<R: Read, F: FnMut(Attestation)>(deser: &mut ser::Deserializer<R>, mut tag: Option<u8>, mut f: F) -> Result<(), Error> {
+    let mut paths = 1usize;
+    while paths > 0 {
+        let next_tag = match tag.take() {
+            Some(tag) => tag,
+            None => deser.read_byte()?
+        };
+        match next_tag {
+            0x00 => {
+                f(Attestation::deserialize(deser)?);
+                paths -= 1;
+            }
+            0xff => paths += 1,
+            tag => {
+                Op::deserialize_with_tag(deser, tag)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The number of bytes occupied by the step at the start of `bytes`
+#[cfg(feature = "rayon")]
+fn step_len(bytes: &[u8], options: ser::ParseOptions) -> Result<usize, Error> {
+    let mut deser = ser::Deserializer::with_options(bytes, options);
+    scan_step(&mut deser, |_| {})?;
+    Ok(deser.bytes_read())
+}
+
+impl Default for Timestamp {
+    /// An empty timestamp (see `Timestamp::new`) on an all-zeroes SHA256-sized
+    /// digest
+    fn default() -> Timestamp {
+        Timestamp::new(vec![0; 32])
+    }
+}
+
+/// Maximum depth of step trees generated by `Arbitrary`
+#[cfg(feature = "arbitrary")]
+const ARBITRARY_DEPTH: usize = 32;
+
+#[cfg(feature = "arbitrary")]
+impl Step {
+    /// Generates a structurally valid step tree on `input`. The last branch of
+    /// a fork may not itself be a fork, as that would be flattened into its
+    /// parent on re-parsing, so forks are never allowed directly under forks.
+    fn arbitrary_on<'a>(u: &mut arbitrary::Unstructured<'a>, input: &[u8], depth: usize, allow_fork: bool) -> arbitrary::Result<Step> {
+        let choice = if depth == 0 { 0 } else { u.int_in_range(0..=3)? };
+        match choice {
+            1 if allow_fork => {
+                let n = u.int_in_range(2..=4)?;
+                let mut next = Vec::with_capacity(n);
+                for _ in 0..n {
+                    next.push(Step::arbitrary_on(u, input, depth - 1, false)?);
+                }
+                Ok(Step { data: StepData::Fork, output: input.to_vec(), next })
+            }
+            1..=3 => {
+                let mut op: Op = u.arbitrary()?;
+                let mut output = op.execute(input);
+                if output.len() > ::op::MAX_OP_LENGTH {
+                    op = Op::Sha256;
+                    output = op.execute(input);
+                }
+                let next = vec![Step::arbitrary_on(u, &output, depth - 1, true)?];
+                Ok(Step { data: StepData::Op(op), output, next })
+            }
+            _ => Ok(Step {
+                data: StepData::Attestation(u.arbitrary()?),
+                output: input.to_vec(),
+                next: vec![]
+            })
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Step {
+    /// Generates a step tree on an arbitrary 32-byte input
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Step> {
+        let input: [u8; 32] = u.arbitrary()?;
+        Step::arbitrary_on(u, &input, ARBITRARY_DEPTH, true)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Timestamp {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Timestamp> {
+        let len = u.int_in_range(1..=64)?;
+        let mut start_digest = Vec::with_capacity(len);
+        for _ in 0..len {
+            start_digest.push(u.arbitrary()?);
+        }
+        let first_step = Step::arbitrary_on(u, &start_digest, ARBITRARY_DEPTH, true)?;
+        Ok(Timestamp { start_digest, first_step })
+    }
+}
+
+/// How many more lines a display may use, and how many steps were left out
+/// for lack of them
+struct LineBudget {
+    left: usize,
+    skipped: usize
+}
+
+fn fmt_recurse<W: fmt::Write>(step: &Step, f: &mut W, depth: usize, first_line: bool, budget: &mut LineBudget) -> fmt::Result {
+    fn indent<W: fmt::Write>(f: &mut W, depth: usize, first_line: bool) -> fmt::Result {
+        if depth == 0 {
+            return Ok(());
+        }
+
+        for _ in 0..depth-1 {
+            f.write_str("    ")?;
+        }
+        if first_line {
+            f.write_str("--->")?;
+        } else {
+            f.write_str("    ")?;
+        }
+        Ok(())
+    }
+
+    let lines = match step.data {
+        StepData::Op(_) => 2,
+        _ => 1
+    };
+    if budget.left < lines {
+        // Once out of lines stop altogether, rather than squeezing in
+        // whatever later steps happen to fit
+        budget.left = 0;
+        step.walk(0, &mut |_, _| budget.skipped += 1);
+        return Ok(());
+    }
+    budget.left -= lines;
+
+    match step.data {
+        StepData::Fork => {
+            indent(f, depth, first_line)?;
+            writeln!(f, "(fork {} ways)", step.next.len())?;
+            for fork in &step.next {
+                fmt_recurse(fork, f, depth + 1, true, budget)?;
+            }
+            Ok(())
+        }
+        StepData::Op(ref op) => {
+            indent(f, depth, first_line)?;
+            writeln!(f, "execute {}", op)?;
+            indent(f, depth, false)?;
+            writeln!(f, " result {}", Hexed(&step.output))?;
+            fmt_recurse(&step.next[0], f, depth, false, budget)
+        }
+        StepData::Attestation(ref attest) => {
+            indent(f, depth, first_line)?;
+            writeln!(f, "result attested by {}", attest)
+        }
+        StepData::Truncated => {
+            indent(f, depth, first_line)?;
+            writeln!(f, "(truncated: too deep to show)")
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut budget = LineBudget {
+            left: f.precision().unwrap_or(usize::MAX),
+            skipped: 0
+        };
+        if budget.left == 0 {
+            self.first_step.walk(0, &mut |_, _| budget.skipped += 1);
+        } else {
+            writeln!(f, "Starting digest: {}", Hexed(&self.start_digest))?;
+            budget.left -= 1;
+            fmt_recurse(&self.first_step, f, 0, false, &mut budget)?;
+        }
+        if budget.skipped > 0 {
+            writeln!(f, "\u{2026} (truncated, {} more steps)", budget.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn root_from_hex(s: &str) -> [u8; 32] {
+        let mut root = [0; 32];
+        root.copy_from_slice(&from_hex(s));
+        root
+    }
+
+    fn attestation_step(attest: Attestation, output: &[u8]) -> Step {
+        Step {
+            data: StepData::Attestation(attest),
+            output: output.to_vec(),
+            next: vec![]
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert!(ots.timestamp.validate().is_ok());
+
+        let digest = vec![0x22; 32];
+        let output = Op::Sha256.execute(&digest);
+        let dangling = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step {
+                data: StepData::Op(Op::Sha256),
+                output: output.clone(),
+                next: vec![]
+            }
+        };
+        assert_matches!(dangling.validate(), Err(Error::DanglingOp));
+        let mut ser = ser::Serializer::new(vec![]);
+        assert!(dangling.serialize(&mut ser).is_err());
+
+        let lone_fork = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step {
+                data: StepData::Fork,
+                output: digest.clone(),
+                next: vec![attestation_step(Attestation::bitcoin(1), &digest)]
+            }
+        };
+        assert_matches!(lone_fork.validate(), Err(Error::BadBranchCount { step: "fork", count: 1 }));
+    }
+
+    #[test]
+    fn eq_ignoring_unknown() {
+        // Simulate a parser that doesn't understand Bitcoin attestations
+        fn forget_bitcoin(step: &mut Step) {
+            if let StepData::Attestation(Attestation::Blockchain { chain: Chain::Bitcoin, height }) = step.data {
+                let mut ser = ser::Serializer::new(vec![]);
+                ser.write_uint(height.into()).unwrap();
+                step.data = StepData::Attestation(Attestation::Unknown {
+                    tag: b"\x05\x88\x96\x0d\x73\xd7\x19\x01".to_vec(),
+                    data: ser.into_inner()
+                });
+            }
+            for next in &mut step.next {
+                forget_bitcoin(next);
+            }
+        }
+
+        let new = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap().timestamp;
+        let mut old = new.clone();
+        forget_bitcoin(&mut old.first_step);
+
+        assert!(old != new);
+        assert!(old.eq_ignoring_unknown(&new));
+        assert!(new.eq_ignoring_unknown(&old));
+
+        // A differing payload is still a difference
+        let mut wrong = old.clone();
+        let mut steps = vec![&mut wrong.first_step];
+        while let Some(step) = steps.pop() {
+            if let StepData::Attestation(Attestation::Unknown { ref mut data, .. }) = step.data {
+                data[0] ^= 1;
+            }
+            steps.extend(step.next.iter_mut());
+        }
+        assert!(!wrong.eq_ignoring_unknown(&new));
+    }
+
+    #[test]
+    fn commitment_for() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let alice = ots.timestamp.commitment_for("https://alice.btc.calendar.opentimestamps.org").unwrap();
+        let bob = ots.timestamp.commitment_for("https://bob.btc.calendar.opentimestamps.org").unwrap();
+        assert!(alice != bob);
+        assert_eq!(
+            format!("{}", Hexed(bob)),
+            "58a4c2578a67a452937f8837da37dafa442aa308ced90d6b7381149c52f2802858337adaa1a92c61d5417206"
+        );
+        assert_eq!(ots.timestamp.commitment_for("https://carol.example.com"), None);
+    }
+
+    #[test]
+    fn replace_attestation() {
+        let bob_uri = "https://bob.btc.calendar.opentimestamps.org";
+        let bob = Attestation::Pending { uri: bob_uri.to_owned() };
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let commitment = ots.timestamp.commitment_for(bob_uri).unwrap().to_vec();
+        let ops = [Op::Append(vec![0xaa; 8]), Op::Sha256];
+        let upgrade = StepData::Attestation(Attestation::bitcoin(449397));
+
+        // A continuation from some other commitment doesn't fit
+        let mut timestamp = ots.timestamp.clone();
+        let wrong = Step::chain(&[0; 32], &ops, upgrade.clone()).unwrap();
+        assert_matches!(timestamp.replace_attestation(&bob, wrong), Err(Error::OutputMismatch { .. }));
+        assert_eq!(timestamp, ots.timestamp);
+
+        let replacement = Step::chain(&commitment, &ops, upgrade).unwrap();
+        assert!(timestamp.replace_attestation(&bob, replacement.clone()).unwrap());
+        assert_eq!(timestamp.commitment_for(bob_uri), None);
+        assert!(timestamp.commitment_for("https://alice.btc.calendar.opentimestamps.org").is_some());
+        assert_eq!(timestamp.anchors(), vec![(Chain::Bitcoin, BlockHeight(449397))]);
+
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let parsed = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), timestamp.start_digest.clone()).unwrap();
+        assert_eq!(parsed, timestamp);
+
+        // Nothing left to replace
+        assert!(!timestamp.replace_attestation(&bob, replacement).unwrap());
+    }
+
+    #[test]
+    fn commitments() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let commitments = ots.timestamp.commitments();
+        assert_eq!(ots.timestamp.attestation_steps().len(), 4);
+        assert_eq!(commitments.len(), 4);
+        assert!(commitments.contains(&from_hex("1a1da26714e8ef3b140c5f461ee52ea6fb5d8ca8e02d73370b5b416265eb1f5e")));
+
+        // Two attestations on the same commitment
+        let digest = vec![0x11; 32];
+        let timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step {
+                data: StepData::Fork,
+                output: digest.clone(),
+                next: vec![
+                    attestation_step(Attestation::bitcoin(449397), &digest),
+                    attestation_step(Attestation::Pending { uri: "https://example.com".to_owned() }, &digest),
+                ]
+            }
+        };
+        assert_eq!(timestamp.commitments(), vec![digest]);
+    }
+
+    #[test]
+    fn registered_executor() {
+        fn xor_ff(input: &[u8]) -> Vec<u8> {
+            input.iter().map(|b| b ^ 0xff).collect()
+        }
+
+        let mut bytes = vec![0x42, 0x00];
+        let mut ser = ser::Serializer::new(vec![]);
+        Attestation::bitcoin(100).serialize(&mut ser).unwrap();
+        bytes.extend(ser.into_inner());
+
+        // Tag 0x43 is never registered
+        let mut unregistered = bytes.clone();
+        unregistered[0] = 0x43;
+        assert_matches!(Timestamp::deserialize(&mut ser::Deserializer::new(&unregistered[..]), vec![0; 32]), Err(Error::BadOpTag(0x43)));
+
+        Op::register_executor(0x42, xor_ff).unwrap();
+        assert_matches!(Op::register_executor(0x08, xor_ff), Err(Error::BadOpTag(0x08)));
+
+        let timestamp = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), vec![0; 32]).unwrap();
+        assert_eq!(timestamp.first_step.data, StepData::Op(Op::Unknown(0x42)));
+        assert_eq!(timestamp.first_step.output, vec![0xff; 32]);
+        assert!(format!("{}", timestamp).contains("Unknown[0x42]()"));
+
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        assert_eq!(ser.into_inner(), bytes);
+    }
+
+    #[test]
+    fn first_bitcoin_height() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        assert_eq!(ots.timestamp.first_bitcoin_height(), Some(BlockHeight(449397)));
+
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_eq!(ots.timestamp.first_bitcoin_height(), None);
+    }
+
+    #[test]
+    fn step_constructors() {
+        let digest = vec![0x11; 32];
+        let appended = Op::Append(vec![0x22; 4]).execute(&digest);
+        let hashed = Op::Sha256.execute(&appended);
+
+        let bitcoin = Step::attestation(Attestation::bitcoin(449397), hashed.clone());
+        let sha256 = Step::op(Op::Sha256, &appended, vec![bitcoin]).unwrap();
+        let append = Step::op(Op::Append(vec![0x22; 4]), &digest, vec![sha256]).unwrap();
+        let pending = Step::attestation(Attestation::Pending { uri: "https://example.com".to_owned() }, digest.clone());
+        let first_step = Step::fork(&digest, vec![append.clone(), pending.clone()]).unwrap();
+        assert_eq!(first_step.next[0].next[0].output, hashed);
+
+        let timestamp = Timestamp { start_digest: digest.clone(), first_step };
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let parsed = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), digest.clone()).unwrap();
+        assert_eq!(parsed, timestamp);
+
+        // Steps which don't continue from the given input are refused
+        assert_matches!(Step::op(Op::Sha256, &digest, vec![Step::attestation(Attestation::bitcoin(1), digest.clone())]), Err(Error::OutputMismatch { depth: 0, .. }));
+        assert_matches!(Step::fork(&hashed, vec![append, pending.clone()]), Err(Error::OutputMismatch { depth: 0, .. }));
+        assert_matches!(Step::fork(&digest, vec![pending]), Err(Error::BadBranchCount { step: "fork", count: 1 }));
+    }
+
+    #[test]
+    fn walk() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let mut count = 0;
+        let mut max_depth = 0;
+        let mut depths = vec![];
+        ots.timestamp.walk(|_, depth| {
+            count += 1;
+            max_depth = max_depth.max(depth);
+            depths.push(depth);
+        });
+        // Append, SHA256, fork, then two branches of four ops and an attestation
+        assert_eq!(count, 13);
+        assert_eq!(max_depth, 7);
+        assert_eq!(depths, vec![0, 1, 2, 3, 4, 5, 6, 7, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn truncate_deep() {
+        // A fork with one path too deep to parse and one shallow one
+        let digest = vec![0; 32];
+        let ops = vec![Op::Sha256; RECURSION_LIMIT + 10];
+        let deep = Step::chain(&digest, &ops, StepData::Attestation(Attestation::bitcoin(1))).unwrap();
+        let shallow = Step::attestation(Attestation::bitcoin(2), digest.clone());
+        let timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step::fork(&digest, vec![deep, shallow.clone()]).unwrap()
+        };
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+
+        assert_matches!(Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), digest.clone()), Err(Error::StackOverflow));
+
+        let options = ser::ParseOptions { truncate_deep: true, ..Default::default() };
+        let mut deser = ser::Deserializer::with_options(&bytes[..], options);
+        let partial = Timestamp::deserialize(&mut deser, digest.clone()).unwrap();
+        deser.check_eof().unwrap();
+        assert_eq!(partial.first_step.next[1], shallow);
+        assert_eq!(partial.anchors(), vec![(Chain::Bitcoin, BlockHeight(2))]);
+        assert!(format!("{}", partial).contains("(truncated: too deep to show)"));
+
+        let mut truncated = 0;
+        partial.walk(|step, depth| if step.data == StepData::Truncated {
+            assert_eq!(depth, RECURSION_LIMIT);
+            truncated += 1;
+        });
+        assert_eq!(truncated, 1);
+
+        // The partial timestamp can't be written back out
+        assert_matches!(partial.serialize(&mut ser::Serializer::new(vec![])), Err(Error::StackOverflow));
+    }
+
+    /// Blocks looked up in a map
+    struct StubBlocks(HashMap<BlockHeight, Vec<Vec<u8>>>);
+
+    impl BlockProvider for StubBlocks {
+        fn transactions(&self, height: BlockHeight) -> Result<Vec<Vec<u8>>, Error> {
+            self.0.get(&height).cloned().ok_or_else(|| Error::Provider(format!("no block {}", height)))
+        }
+    }
+
+    /// Block headers with the given Merkle roots and times
+    struct StubHeaders(HashMap<BlockHeight, ([u8; 32], u32)>);
+
+    impl BlockHeaderProvider for StubHeaders {
+        fn merkle_root(&self, height: BlockHeight) -> Result<[u8; 32], Error> {
+            self.0.get(&height).map(|header| header.0).ok_or_else(|| Error::Provider(format!("no block {}", height)))
+        }
+
+        fn block_time(&self, height: BlockHeight) -> Result<u32, Error> {
+            self.0.get(&height).map(|header| header.1).ok_or_else(|| Error::Provider(format!("no block {}", height)))
+        }
+    }
+
+    #[test]
+    fn earliest_time() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let mut roots = HashMap::new();
+        for step in ots.timestamp.attestation_steps() {
+            if let StepData::Attestation(Attestation::Blockchain { height, .. }) = step.data {
+                let mut root = [0; 32];
+                root.copy_from_slice(&step.output);
+                roots.insert(height, root);
+            }
+        }
+
+        // The later block may well have the earlier time
+        let mut headers = HashMap::new();
+        headers.insert(BlockHeight(449397), (roots[&BlockHeight(449397)], 1484687409));
+        headers.insert(BlockHeight(449399), (roots[&BlockHeight(449399)], 1484687400));
+        let provider = StubHeaders(headers);
+        assert_eq!(ots.timestamp.earliest_time(&provider).unwrap(), Some(1484687400));
+
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_eq!(small.timestamp.earliest_time(&provider).unwrap(), None);
+
+        // Times are only taken from blocks which verify
+        let mut headers = provider.0.clone();
+        headers.get_mut(&BlockHeight(449399)).unwrap().0 = [0; 32];
+        assert_matches!(ots.timestamp.earliest_time(&StubHeaders(headers)), Err(Error::MerkleRootMismatch { height: BlockHeight(449399) }));
+    }
+
+    #[test]
+    fn verify_bitcoin_tx() {
+        // A block of two transactions, the first committing to the digest
+        let digest = vec![0x55; 32];
+        let prefix = b"\x01\x00\x00\x00 transaction prefix".to_vec();
+        let suffix = b"transaction suffix".to_vec();
+        let tx = [&prefix[..], &digest[..], &suffix[..]].concat();
+        let other_tx = b"another transaction".to_vec();
+        let other_txid = sha256d::Hash::hash(&other_tx).to_byte_array().to_vec();
+        let ops = [
+            Op::Prepend(prefix), Op::Append(suffix), Op::Sha256, Op::Sha256,
+            Op::Append(other_txid), Op::Sha256, Op::Sha256
+        ];
+        let timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step::chain(&digest, &ops, StepData::Attestation(Attestation::bitcoin(1))).unwrap()
+        };
+        assert_eq!(timestamp.bitcoin_transactions(), vec![(BlockHeight(1), Some(tx.clone()))]);
+
+        let mut blocks = HashMap::new();
+        blocks.insert(BlockHeight(1), vec![tx.clone(), other_tx.clone()]);
+        assert_eq!(timestamp.verify_bitcoin_tx(&StubBlocks(blocks.clone())).unwrap(), vec![BlockHeight(1)]);
+
+        // The transaction is missing, or the block differs from the path
+        blocks.insert(BlockHeight(1), vec![other_tx.clone()]);
+        assert_matches!(timestamp.verify_bitcoin_tx(&StubBlocks(blocks.clone())), Err(Error::TxNotInBlock { height: BlockHeight(1) }));
+        blocks.insert(BlockHeight(1), vec![tx.clone(), other_tx, b"third".to_vec()]);
+        assert_matches!(timestamp.verify_bitcoin_tx(&StubBlocks(blocks)), Err(Error::MerkleRootMismatch { height: BlockHeight(1) }));
+
+        // LARGE_TEST's calendar transactions are found, but a block of just
+        // one of them doesn't have the real block's root
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let found = ots.timestamp.bitcoin_transactions();
+        assert_eq!(found.len(), 2);
+        let (height, large_tx) = found[0].clone();
+        assert_eq!(height, BlockHeight(449399));
+        let large_tx = large_tx.unwrap();
+        // Version 1, one input
+        assert_eq!(&large_tx[..5], b"\x01\x00\x00\x00\x01");
+
+        let mut blocks = HashMap::new();
+        blocks.insert(BlockHeight(449399), vec![large_tx]);
+        assert_matches!(ots.timestamp.verify_bitcoin_tx(&StubBlocks(blocks)), Err(Error::MerkleRootMismatch { height: BlockHeight(449399) }));
+        assert_matches!(ots.timestamp.verify_bitcoin_tx(&StubBlocks(HashMap::new())), Err(Error::Provider(_)));
+    }
+
+    #[test]
+    fn deduplicate_subtrees() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let mut timestamp = ots.timestamp.clone();
+        assert_eq!(timestamp.deduplicate_subtrees(), 0);
+        assert_eq!(timestamp, ots.timestamp);
+
+        // Fork the SMALL_TEST tree with a copy of itself, and one of its
+        // branches with a copy of that branch
+        let digest = timestamp.start_digest.clone();
+        let mut doubled = Timestamp::fork(digest, vec![timestamp.clone(), timestamp.clone()]).unwrap();
+        let fork = &mut doubled.first_step.next[0].next[0].next[0];
+        assert_eq!(fork.data, StepData::Fork);
+        let branch = fork.next[0].clone();
+        fork.next.push(branch);
+        assert_eq!(doubled.branch_count(), 5);
+
+        assert_eq!(doubled.deduplicate_subtrees(), 2);
+        assert_eq!(doubled, timestamp);
+        assert!(doubled.serialized_len() < Timestamp::fork(timestamp.start_digest.clone(), vec![timestamp.clone(), timestamp]).unwrap().serialized_len());
+    }
+
+    #[test]
+    fn rewrite_uri() {
+        let bob = "https://bob.btc.calendar.opentimestamps.org";
+        let alice = "https://alice.btc.calendar.opentimestamps.org";
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let bob_commitment = ots.timestamp.commitment_for(bob).unwrap().to_vec();
+
+        let mut timestamp = ots.timestamp.clone();
+        assert_eq!(timestamp.rewrite_uri(bob, "https://bob.example.org").unwrap(), 1);
+        assert_eq!(timestamp.commitment_for(bob), None);
+        assert_eq!(timestamp.commitment_for("https://bob.example.org"), Some(&bob_commitment[..]));
+        assert_eq!(timestamp.commitment_for(alice), ots.timestamp.commitment_for(alice));
+
+        // Rewriting a prefix catches every calendar under it
+        assert_eq!(timestamp.rewrite_uri("https://", "http://").unwrap(), 2);
+        assert_eq!(timestamp.rewrite_uri("https://", "http://").unwrap(), 0);
+
+        let before = timestamp.clone();
+        assert_matches!(timestamp.rewrite_uri("http://", "http://user@"), Err(Error::InvalidUriChar('@')));
+        assert_eq!(timestamp, before);
+    }
+
+    #[test]
+    fn to_pending() {
+        let uri = "https://calendar.example.com";
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let pending = ots.timestamp.to_pending(uri);
+
+        assert!(pending.anchors().is_empty());
+        assert_eq!(pending.branch_count(), ots.timestamp.branch_count());
+        assert_eq!(pending.commitments(), ots.timestamp.commitments());
+        // The former Bitcoin leaves now point at the given calendar
+        assert_eq!(pending.commitment_for(uri), Some(&from_hex("1a1da26714e8ef3b140c5f461ee52ea6fb5d8ca8e02d73370b5b416265eb1f5e")[..]));
+        pending.verify_outputs().unwrap();
+
+        // Pending timestamps are unchanged
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_eq!(small.timestamp.to_pending(uri), small.timestamp);
+    }
+
+    #[test]
+    fn bare_round_trip() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        // The timestamp is what follows the header
+        let body = &::tests::LARGE_TEST[::tests::LARGE_TEST.len() - ots.timestamp.serialized_len()..];
+        assert_eq!(body[0], 0xf0);
+
+        let timestamp = Timestamp::from_reader_with_digest(body, ots.timestamp.start_digest.clone()).unwrap();
+        assert_eq!(timestamp, ots.timestamp);
+        let mut written = vec![];
+        assert_eq!(timestamp.to_writer(&mut written).unwrap(), body.len());
+        assert_eq!(&written[..], body);
+
+        let mut trailing = body.to_vec();
+        trailing.push(0);
+        assert_matches!(Timestamp::from_reader_with_digest(&trailing[..], ots.timestamp.start_digest.clone()), Err(Error::TrailingBytes));
+    }
+
+    #[test]
+    fn op_tags() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        // SHA256, append and prepend
+        assert_eq!(ots.timestamp.op_tags().into_iter().collect::<Vec<_>>(), vec![0x08, 0xf0, 0xf1]);
+
+        let digest = vec![0x44; 32];
+        let timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step::chain(&digest, &[Op::Reverse, Op::Ripemd160], StepData::Attestation(Attestation::bitcoin(1))).unwrap()
+        };
+        assert_eq!(timestamp.op_tags().into_iter().collect::<Vec<_>>(), vec![0x03, 0xf2]);
+        assert!(Timestamp::new(digest).op_tags().is_empty());
+    }
+
+    #[test]
+    fn unknown_attestation_tags() {
+        let large = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        assert!(large.timestamp.unknown_attestation_tags().is_empty());
+
+        let digest = vec![0x44; 32];
+        let unknown = |tag: &[u8], data: &[u8]| Attestation::Unknown { tag: tag.to_vec(), data: data.to_vec() };
+        let mut timestamp = Timestamp::new(digest);
+        timestamp.attach_path(&[Op::Sha256], unknown(b"newchain", b"block 1")).unwrap();
+        timestamp.attach_path(&[Op::Reverse], Attestation::bitcoin(1)).unwrap();
+        timestamp.attach_path(&[Op::Sha1], unknown(b"\x01\x02\x03\x04\x05\x06\x07\x08", b"")).unwrap();
+        timestamp.attach_path(&[Op::Sha256, Op::Sha256], unknown(b"newchain", b"block 2")).unwrap();
+        assert_eq!(timestamp.unknown_attestation_tags(), vec![b"newchain".to_vec(), vec![1, 2, 3, 4, 5, 6, 7, 8]]);
+    }
+
+    #[test]
+    fn is_linear() {
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert!(!small.timestamp.is_linear());
+        assert_eq!(small.timestamp.branch_count(), 2);
+
+        let large = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        assert!(!large.timestamp.is_linear());
+        assert_eq!(large.timestamp.branch_count(), 4);
+
+        let digest = vec![0x44; 32];
+        let linear = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step::chain(&digest, &[Op::Append(vec![1; 16]), Op::Sha256], StepData::Attestation(Attestation::bitcoin(1))).unwrap()
+        };
+        assert!(linear.is_linear());
+        assert_eq!(linear.branch_count(), 1);
+    }
+
+    #[test]
+    fn prune() {
+        let mut small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_matches!(small.timestamp.prune(), Err(Error::NotAttested));
+
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let mut timestamp = ots.timestamp.clone();
+        assert_eq!(timestamp.prune().unwrap(), 2);
+        assert_eq!(timestamp.anchors(), ots.timestamp.anchors());
+        assert_eq!(timestamp.attestation_steps().len(), 2);
+        timestamp.validate().unwrap();
+        timestamp.verify_outputs().unwrap();
+        assert_eq!(timestamp.prune().unwrap(), 0);
+
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let parsed = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), timestamp.start_digest.clone()).unwrap();
+        assert_eq!(parsed, timestamp);
+    }
+
+    #[test]
+    fn retain_attestations() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let is_bitcoin = |attest: &Attestation| matches!(*attest, Attestation::Blockchain { chain: Chain::Bitcoin, .. });
+
+        let mut timestamp = ots.timestamp.clone();
+        assert_eq!(timestamp.retain_attestations(is_bitcoin).unwrap(), 2);
+        let mut pruned = ots.timestamp.clone();
+        pruned.prune().unwrap();
+        assert_eq!(timestamp, pruned);
+
+        // Just the newer of the two blocks
+        let mut timestamp = ots.timestamp.clone();
+        timestamp.retain_attestations(|attest| *attest == Attestation::bitcoin(449399)).unwrap();
+        assert_eq!(timestamp.anchors(), vec![(Chain::Bitcoin, BlockHeight(449399))]);
+        assert!(timestamp.is_linear());
+        timestamp.validate().unwrap();
+        timestamp.verify_outputs().unwrap();
+
+        let mut timestamp = ots.timestamp.clone();
+        assert_matches!(timestamp.retain_attestations(|attest| *attest == Attestation::litecoin(1)), Err(Error::NothingRetained));
+        assert_eq!(timestamp, ots.timestamp);
+    }
+
+    #[test]
+    fn fork() {
+        let digest = vec![0x33; 32];
+        let pending = |uri: &str, nonce: u8| Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step::chain(&digest, &[Op::Append(vec![nonce; 16]), Op::Sha256], StepData::Attestation(Attestation::Pending { uri: uri.to_owned() })).unwrap()
+        };
+        let alice = pending("https://alice.example.com", 1);
+        let bob = pending("https://bob.example.com", 2);
+
+        let forked = Timestamp::fork(digest.clone(), vec![alice.clone(), bob.clone()]).unwrap();
+        assert_eq!(forked.first_step.data, StepData::Fork);
+        assert_eq!(forked.first_step.next, vec![alice.first_step.clone(), bob.first_step.clone()]);
+        forked.validate().unwrap();
+        forked.verify_outputs().unwrap();
+
+        // Forking onto a fork flattens it
+        let carol = pending("https://carol.example.com", 3);
+        let three = Timestamp::fork(digest.clone(), vec![forked, carol]).unwrap();
+        assert_eq!(three.first_step.next.len(), 3);
+
+        assert_matches!(Timestamp::fork(digest.clone(), vec![alice.clone()]), Err(Error::BadBranchCount { step: "fork", count: 1 }));
+        assert_matches!(Timestamp::fork(vec![0; 32], vec![alice, bob]), Err(Error::CommitmentMismatch { .. }));
+    }
+
+    #[test]
+    fn verify_outputs() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        ots.timestamp.verify_outputs().unwrap();
+
+        // Append, then SHA256, then the fork at depth 2
+        let mut corrupt = ots.timestamp.clone();
+        assert_eq!(corrupt.first_step.next[0].next[0].data, StepData::Fork);
+        corrupt.first_step.next[0].next[0].output = vec![0; 32];
+        assert_matches!(corrupt.verify_outputs(), Err(Error::OutputMismatch { depth: 2, ref got, .. }) if got == &vec![0; 32]);
+    }
+
+    #[test]
+    fn merge_continuation() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let alice_uri = "https://alice.btc.calendar.opentimestamps.org";
+        let commitment = ots.timestamp.commitment_for(alice_uri).unwrap().to_vec();
+        let ops = [Op::Prepend(vec![0xbb; 8]), Op::Sha256];
+        let continuation = Timestamp {
+            start_digest: commitment.clone(),
+            first_step: Step::chain(&commitment, &ops, StepData::Attestation(Attestation::bitcoin(449397))).unwrap()
+        };
+
+        let mut timestamp = ots.timestamp.clone();
+        assert_matches!(timestamp.merge_continuation(&[0; 32], continuation.clone()), Err(Error::CommitmentMismatch { .. }));
+        let elsewhere = Timestamp {
+            start_digest: vec![0; 32],
+            first_step: Step::chain(&[0; 32], &ops, StepData::Attestation(Attestation::bitcoin(449397))).unwrap()
+        };
+        assert_matches!(timestamp.merge_continuation(&[0; 32], elsewhere), Err(Error::CommitmentNotFound));
+        assert_eq!(timestamp, ots.timestamp);
+
+        timestamp.merge_continuation(&commitment, continuation).unwrap();
+        assert_eq!(timestamp.anchors(), vec![(Chain::Bitcoin, BlockHeight(449397))]);
+        assert_eq!(timestamp.commitment_for(alice_uri), Some(&commitment[..]));
+        assert_eq!(timestamp.attestation_steps().len(), 3);
+
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let parsed = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), timestamp.start_digest.clone()).unwrap();
+        assert_eq!(parsed, timestamp);
+    }
+
+    #[test]
+    fn default() {
+        let timestamp = Timestamp::default();
+        assert!(timestamp.is_empty());
+        assert_eq!(timestamp.start_digest, vec![0; 32]);
+        assert_eq!(timestamp.first_step.output, timestamp.start_digest);
+        assert_matches!(timestamp.validate(), Err(Error::BadBranchCount { step: "fork", count: 0 }));
+
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert!(!ots.timestamp.is_empty());
+    }
+
+    #[test]
+    fn verify_bitcoin_with_roots() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+
+        let mut roots = HashMap::new();
+        assert_eq!(ots.timestamp.verify_bitcoin_with_roots(&roots).unwrap(), Vec::<BlockHeight>::new());
+
+        roots.insert(BlockHeight(449399), root_from_hex("1a1da26714e8ef3b140c5f461ee52ea6fb5d8ca8e02d73370b5b416265eb1f5e"));
+        assert_eq!(ots.timestamp.verify_bitcoin_with_roots(&roots).unwrap(), vec![BlockHeight(449399)]);
+
+        roots.insert(BlockHeight(449397), root_from_hex("7c17a8a0d6bc1da3604ecd442fc38869983290dcbc4876bdf0fcf133791ff218"));
+        assert_eq!(ots.timestamp.verify_bitcoin_with_roots(&roots).unwrap(), vec![BlockHeight(449399), BlockHeight(449397)]);
+
+        roots.insert(BlockHeight(449397), [0; 32]);
+        assert_matches!(ots.timestamp.verify_bitcoin_with_roots(&roots), Err(Error::MerkleRootMismatch { height: BlockHeight(449397) }));
+
+        // The root as a block explorer would display it
+        let mut reversed = root_from_hex("7c17a8a0d6bc1da3604ecd442fc38869983290dcbc4876bdf0fcf133791ff218");
+        reversed.reverse();
+        roots.insert(BlockHeight(449397), reversed);
+        assert_matches!(ots.timestamp.verify_bitcoin_with_roots(&roots), Err(Error::ReversedRoot { height: BlockHeight(449397) }));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn from_slice_parallel() {
+        for bytes in &[::tests::SMALL_TEST, ::tests::LARGE_TEST] {
+            let sequential = ::DetachedTimestampFile::from_reader(*bytes).unwrap();
+            let parallel = ::DetachedTimestampFile::from_slice_parallel(bytes).unwrap();
+            assert_eq!(parallel, sequential);
+
+            assert_matches!(::DetachedTimestampFile::from_slice_parallel(&bytes[..bytes.len() - 1]), Err(Error::Io(_)) | Err(Error::Truncated { .. }));
+        }
+
+        let mut trailing = ::tests::LARGE_TEST.to_vec();
+        trailing.push(0);
+        assert_matches!(::DetachedTimestampFile::from_slice_parallel(&trailing), Err(Error::TrailingBytes));
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_round_trip() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        // xorshift, to get some varied bytes without pulling in an RNG
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..32 {
+            let data: Vec<u8> = (0..4096).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect();
+
+            let mut u = Unstructured::new(&data);
+            let timestamp = Timestamp::arbitrary(&mut u).unwrap();
+            timestamp.validate().unwrap();
+
+            let mut ser = ser::Serializer::new(vec![]);
+            timestamp.serialize(&mut ser).unwrap();
+            let bytes = ser.into_inner();
+            let mut deser = ser::Deserializer::new(&bytes[..]);
+            let parsed = Timestamp::deserialize(&mut deser, timestamp.start_digest.clone()).unwrap();
+            deser.check_eof().unwrap();
+            assert_eq!(parsed, timestamp);
+        }
+    }
+
+    #[test]
+    fn op_length_limits() {
+        fn parse(ops: &[Op]) -> Result<Timestamp, Error> {
+            // Written by hand, bypassing the argument checks in `Op::serialize`
+            let mut ser = ser::Serializer::new(vec![]);
+            for op in ops {
+                ser.write_byte(op.tag()).unwrap();
+                if let Op::Append(ref data) | Op::Prepend(ref data) = *op {
+                    ser.write_bytes(data).unwrap();
+                }
+            }
+            ser.write_byte(0x00).unwrap();
+            Attestation::bitcoin(100).serialize(&mut ser).unwrap();
+            let bytes = ser.into_inner();
+            Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), vec![0; 32])
+        }
+
+        // An argument over the limit is a framing error
+        assert_matches!(parse(&[Op::Append(vec![0; ::op::MAX_OP_LENGTH + 1])]), Err(Error::BadLength { max: ::op::MAX_OP_LENGTH, val, .. }) if val == ::op::MAX_OP_LENGTH + 1);
+
+        // Arguments within the limit whose results grow past it are not
+        assert_matches!(parse(&[Op::Append(vec![0; ::op::MAX_OP_LENGTH - 32]), Op::Prepend(vec![1])]), Err(Error::OpOutputTooLong { op: "prepend", len }) if len == ::op::MAX_OP_LENGTH + 1);
+        assert_matches!(parse(&[Op::Append(vec![0; 2048]), Op::Hexlify]), Err(Error::OpOutputTooLong { op: "hexlify", len }) if len == 2 * (32 + 2048));
+
+        // Results exactly at the limit are fine
+        parse(&[Op::Append(vec![0; ::op::MAX_OP_LENGTH - 32])]).unwrap();
+    }
+
+    #[test]
+    fn verify_leaf() {
+        // A Merkle tree of four documents, hashing pairs as SHA256(left || right)
+        let leaves: Vec<Vec<u8>> = (0u8..4).map(|i| Op::Sha256.execute(&[i])).collect();
+        let node = |left: &[u8], right: &[u8]| Op::Sha256.execute(&[left, right].concat());
+        let pairs = [node(&leaves[0], &leaves[1]), node(&leaves[2], &leaves[3])];
+        let root = node(&pairs[0], &pairs[1]);
+
+        let mut timestamp = Timestamp::new(root.clone());
+        timestamp.attach_path(&[Op::Sha256], Attestation::bitcoin(1)).unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            let sibling = |hash: &[u8], on_left: bool| if on_left { Op::Prepend(hash.to_vec()) } else { Op::Append(hash.to_vec()) };
+            let path = [
+                sibling(&leaves[i ^ 1], i % 2 == 1), Op::Sha256,
+                sibling(&pairs[(i / 2) ^ 1], i / 2 == 1), Op::Sha256,
+            ];
+            assert_eq!(timestamp.verify_leaf(leaf, &path).unwrap(), root);
+
+            // The path of one leaf doesn't work for another
+            assert_matches!(timestamp.verify_leaf(&leaves[i ^ 1], &path), Err(Error::CommitmentMismatch { ref expected, .. }) if *expected == root);
+        }
+    }
+
+    #[test]
+    fn split_at_commitment() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let (ops, rest) = ots.timestamp.split_at_commitment();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[1], Op::Sha256);
+        assert_eq!(rest.first_step.data, StepData::Fork);
+        assert_eq!(
+            format!("{}", Hexed(&rest.start_digest)),
+            "e1ae951b5744348aaf577a341e834464680d1c0afdea639715f148a0379d6593"
+        );
+
+        // Recombine
+        let mut outputs = vec![];
+        let mut digest = ots.timestamp.start_digest.clone();
+        for op in &ops {
+            digest = op.execute(&digest);
+            outputs.push(digest.clone());
+        }
+        assert_eq!(digest, rest.start_digest);
+        let mut step = rest.first_step.clone();
+        for (op, output) in ops.into_iter().zip(outputs).rev() {
+            step = Step {
+                data: StepData::Op(op),
+                output,
+                next: vec![step]
+            };
+        }
+        let recombined = Timestamp {
+            start_digest: ots.timestamp.start_digest.clone(),
+            first_step: step
+        };
+        assert_eq!(recombined, ots.timestamp);
+
+        // A timestamp that begins with an attestation has no client-side ops
+        let (ops, same) = rest.split_at_commitment();
+        assert!(ops.is_empty());
+        assert_eq!(same, rest);
+    }
+
+    #[test]
+    fn extend() {
+        let digest = vec![0x33; 32];
+        let pending = Attestation::Pending { uri: "https://alice.btc.calendar.opentimestamps.org".to_owned() };
+        let mut timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: attestation_step(pending.clone(), &digest)
+        };
+
+        let ops = [Op::Append(vec![1, 2, 3]), Op::Sha256];
+        timestamp.extend(&ops).unwrap();
+        assert!(timestamp.validate().is_ok());
+
+        let mut appended = digest.clone();
+        appended.extend(&[1, 2, 3]);
+        let hashed = Op::Sha256.execute(&appended);
+        let first = &timestamp.first_step;
+        assert_eq!(first.data, StepData::Op(ops[0].clone()));
+        assert_eq!(first.output, appended);
+        assert_eq!(first.next[0].data, StepData::Op(Op::Sha256));
+        assert_eq!(first.next[0].output, hashed);
+        assert_eq!(first.next[0].next[0].data, StepData::Attestation(pending));
+        assert_eq!(first.next[0].next[0].output, hashed);
+
+        let mut complete = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap().timestamp;
+        let original = complete.clone();
+        assert_matches!(complete.extend(&ops), Err(Error::AlreadyAttested));
+        assert_eq!(complete, original);
+
+        // Outputs too long to parse back are refused, even if some branches
+        // could be extended before the problem is found
+        let mut small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap().timestamp;
+        let original = small.clone();
+        assert_matches!(small.extend(&vec![Op::Hexlify; 8]), Err(Error::OpOutputTooLong { op: "hexlify", .. }));
+        assert_eq!(small, original);
+
+        assert_matches!(small.attach_path(&[Op::Sha256, Op::Unknown(0x44)], Attestation::bitcoin(1)), Err(Error::BadOpTag(0x44)));
+        assert_eq!(small, original);
+    }
+
+    #[test]
+    fn anchors() {
+        let digest = vec![0x11; 32];
+        let timestamp = Timestamp {
+            start_digest: digest.clone(),
+            first_step: Step {
+                data: StepData::Fork,
+                output: digest.clone(),
+                next: vec![
+                    attestation_step(Attestation::bitcoin(449397), &digest),
+                    attestation_step(Attestation::Pending { uri: "https://alice.btc.calendar.opentimestamps.org".to_owned() }, &digest),
+                    attestation_step(Attestation::litecoin(1234567), &digest),
+                ]
+            }
+        };
+
+        assert_eq!(
+            timestamp.anchors(),
+            vec![(Chain::Bitcoin, BlockHeight(449397)), (Chain::Litecoin, BlockHeight(1234567))]
+        );
+
+        // Round-trip through the wire format to exercise the Litecoin tag
+        let mut ser = ser::Serializer::new(vec![]);
+        timestamp.serialize(&mut ser).unwrap();
+        let bytes = ser.into_inner();
+        let mut deser = ser::Deserializer::new(&bytes[..]);
+        let decoded = Timestamp::deserialize(&mut deser, digest).unwrap();
+        assert_eq!(decoded, timestamp);
+    }
+
+    #[test]
+    fn completeness() {
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_eq!(small.timestamp.completeness(), 0.0);
+
+        let mut large = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap();
+        let completeness = large.timestamp.completeness();
+        assert!(completeness > 0.0 && completeness < 1.0);
+
+        large.timestamp.prune().unwrap();
+        assert_eq!(large.timestamp.completeness(), 1.0);
+    }
+
+    #[test]
+    fn map_ops() {
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let timestamp = &ots.timestamp;
+
+        // Mapping to the same ops changes nothing
+        assert_eq!(&timestamp.map_ops(Op::clone).unwrap(), timestamp);
+
+        let zeroed = timestamp.map_ops(|op| match *op {
+            Op::Append(ref data) => Op::Append(vec![0; data.len()]),
+            ref op => op.clone()
+        }).unwrap();
+        zeroed.verify_outputs().unwrap();
+        assert_eq!(zeroed.first_step.data, StepData::Op(Op::Append(vec![0; 16])));
+        assert_ne!(zeroed.first_step.output, timestamp.first_step.output);
+        assert_eq!(zeroed.attestation_steps().len(), timestamp.attestation_steps().len());
+        assert_eq!(zeroed.op_tags(), timestamp.op_tags());
+
+        assert_matches!(timestamp.map_ops(|_| Op::Append(vec![0; op::MAX_OP_LENGTH])), Err(Error::OpOutputTooLong { .. }));
+    }
+
+    /// A calendar which has timestamped everything in Bitcoin block 1
+    struct StubCalendar;
+
+    impl CalendarProvider for StubCalendar {
+        fn timestamp(&self, uri: &str, commitment: &[u8]) -> Result<Timestamp, Error> {
+            if uri.contains("bob") {
+                return Err(Error::Provider("bob is down".to_owned()));
+            }
+            let output = Op::Sha256.execute(commitment);
+            Ok(Timestamp {
+                start_digest: commitment.to_vec(),
+                first_step: Step::op(Op::Sha256, commitment, vec![Step::attestation(Attestation::bitcoin(1), output)])?
+            })
+        }
+    }
+
+    #[test]
+    fn upgrade() {
+        let alice = "https://alice.btc.calendar.opentimestamps.org";
+        let bob = "https://bob.btc.calendar.opentimestamps.org";
+        let evil = "https://calendar.example.com";
+
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        let mut timestamp = ots.timestamp.clone();
+        let report = timestamp.upgrade(&StubCalendar, &Allowlist::default());
+        assert_eq!(report.upgraded, vec![alice.to_owned()]);
+        assert!(report.skipped.is_empty());
+        assert_matches!(&report.failed[..], [(uri, Error::Provider(_))] if uri == bob);
+        assert_eq!(timestamp.anchors(), vec![(Chain::Bitcoin, BlockHeight(1))]);
+        assert!(timestamp.commitment_for(bob).is_some());
+        timestamp.verify_outputs().unwrap();
+
+        // A calendar off the allowlist is never contacted
+        let mut timestamp = ots.timestamp.clone();
+        timestamp.rewrite_uri(alice, evil).unwrap();
+        let report = timestamp.upgrade(&StubCalendar, &Allowlist::default());
+        assert!(report.upgraded.is_empty());
+        assert_eq!(report.skipped, vec![evil.to_owned()]);
+        assert!(timestamp.commitment_for(evil).is_some());
+        assert!(timestamp.anchors().is_empty());
+
+        let report = timestamp.upgrade(&StubCalendar, &Allowlist::new(&["https://calendar.example.com"]));
+        assert_eq!(report.upgraded, vec![evil.to_owned()]);
+        assert_eq!(report.skipped, vec![bob.to_owned()]);
+    }
+
+    #[test]
+    fn degenerate_fork() {
+        let digest = vec![0x33; 32];
+        let mut ser = ser::Serializer::new(vec![]);
+        Attestation::bitcoin(1).serialize(&mut ser).unwrap();
+        let attest = ser.into_inner();
+
+        // A fork marker followed by a single branch can only be a proof which
+        // was cut short, since every 0xff promises another branch
+        let mut bytes = vec![0xff, 0x00];
+        bytes.extend_from_slice(&attest);
+        let e = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), digest.clone()).unwrap_err();
+        assert!(e.is_truncation(), "unexpected error {:?}", e);
+        #[cfg(feature = "rayon")]
+        {
+            let e = Timestamp::from_slice_parallel(&bytes, digest.clone(), ser::ParseOptions::default()).unwrap_err();
+            assert!(e.is_truncation(), "unexpected error {:?}", e);
+        }
+
+        // With a second branch it's fine
+        bytes.push(0x00);
+        bytes.extend_from_slice(&attest);
+        let timestamp = Timestamp::deserialize(&mut ser::Deserializer::new(&bytes[..]), digest).unwrap();
+        assert_eq!(timestamp.first_step.next.len(), 2);
+    }
+
+    #[test]
+    fn display_truncated() {
+        let ops = vec![Op::Sha256; 2000];
+        let timestamp = Timestamp {
+            start_digest: vec![0; 32],
+            first_step: Step::chain(&[0; 32], &ops, StepData::Attestation(Attestation::Pending { uri: "https://example.com".to_owned() })).unwrap()
+        };
+        assert_eq!(timestamp.to_string().lines().count(), 4002);
+
+        let display = timestamp.display_truncated(100);
+        let lines: Vec<&str> = display.lines().collect();
+        // Each op takes two lines, so the display stops a line short
+        assert_eq!(lines.len(), 100);
+        assert_eq!(lines[99], "\u{2026} (truncated, 1952 more steps)");
+        assert_eq!(format!("{:.100}", timestamp), display);
+
+        // Short timestamps are shown in full
+        let ots = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap();
+        assert_eq!(ots.timestamp.display_truncated(100), ots.timestamp.to_string());
+        assert!(ots.timestamp.display_truncated(3).ends_with("\n\u{2026} (truncated, 12 more steps)\n"));
+    }
+
+    #[test]
+    fn can_merge() {
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap().timestamp;
+        let large = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap().timestamp;
+        let pending = small.to_pending("https://example.com");
+        assert!(small.can_merge(&pending));
+        assert!(pending.can_merge(&small));
+        assert!(Timestamp::fork(small.start_digest.clone(), vec![small.clone(), pending]).is_ok());
+
+        assert!(!small.can_merge(&large));
+        assert!(!large.can_merge(&small));
+        assert!(Timestamp::fork(small.start_digest.clone(), vec![small, large]).is_err());
+    }
+
+    #[test]
+    fn count_attestations() {
+        let small = ::DetachedTimestampFile::from_reader(::tests::SMALL_TEST).unwrap().timestamp;
+        assert_eq!(small.count_pending(), 2);
+        assert_eq!(small.count_complete(), 0);
+
+        let large = ::DetachedTimestampFile::from_reader(::tests::LARGE_TEST).unwrap().timestamp;
+        assert_eq!(large.count_pending(), 2);
+        assert_eq!(large.count_complete(), 2);
+    }
+
+    #[test]
+    fn unexecutable_op() {
+        // An unknown op with no registered executor is an error, not a panic
+        let input = vec![0x55; 32];
+        let unknown = Step {
+            data: StepData::Op(Op::Unknown(0x44)),
+            output: input.clone(),
+            next: vec![Step::attestation(Attestation::bitcoin(1), input.clone())]
+        };
+        let other = Step::attestation(Attestation::bitcoin(2), input.clone());
+        assert_matches!(Step::fork(&input, vec![unknown.clone(), other]), Err(Error::BadOpTag(0x44)));
+        assert_matches!(Step::op(Op::Sha256, &[], vec![unknown]), Err(Error::BadOpTag(0x44)));
+    }
+}
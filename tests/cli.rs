@@ -0,0 +1,260 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # Command-line tests
+//!
+//! Runs the binaries against timestamps written to temporary files
+//!
+
+extern crate opentimestamps as ots;
+
+use std::process::{Command, Output};
+use std::{env, fs};
+
+use ots::attestation::Attestation;
+use ots::op::Op;
+use ots::ser::DigestType;
+use ots::timestamp::{Step, StepData};
+use ots::{DetachedTimestampFile, Timestamp};
+
+use temp_path::TempPath;
+
+#[cfg(feature = "calendar")]
+#[path = "common/mock_http.rs"]
+mod mock_http;
+#[path = "common/temp_path.rs"]
+mod temp_path;
+
+/// Builds a valid timestamp consisting of `depth` SHA256 ops in a row
+fn deep_timestamp(depth: usize) -> Vec<u8> {
+    let start_digest = vec![0x5a; 32];
+    let mut digest = start_digest.clone();
+    let mut outputs = vec![];
+    for _ in 0..depth {
+        digest = Op::Sha256.execute(&digest);
+        outputs.push(digest.clone());
+    }
+
+    let mut step = Step {
+        data: StepData::Attestation(Attestation::bitcoin(100)),
+        output: digest,
+        next: vec![]
+    };
+    for output in outputs.into_iter().rev() {
+        step = Step {
+            data: StepData::Op(Op::Sha256),
+            output,
+            next: vec![step]
+        };
+    }
+
+    let ots = DetachedTimestampFile {
+        digest_type: DigestType::Sha256,
+        timestamp: Timestamp {
+            start_digest,
+            first_step: step
+        }
+    };
+    let mut bytes = vec![];
+    ots.to_writer(&mut bytes).unwrap();
+    bytes
+}
+
+/// The SMALL_TEST fixture of the library tests: two pending attestations
+const SMALL_TEST: &[u8] = include_bytes!("data/small_test.ots");
+
+/// The LARGE_TEST fixture of the library tests: two pending attestations,
+/// each alongside a Bitcoin attestation
+const LARGE_TEST: &[u8] = include_bytes!("data/large_test.ots");
+
+fn ots(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ots"))
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap()
+}
+
+fn ots_info(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ots-info"))
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn max_depth() {
+    let file = TempPath::with_contents("deep.ots", &deep_timestamp(20));
+    let path = file.0.to_str().unwrap();
+
+    let output = ots_info(&[path]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Bitcoin block 100"));
+
+    let output = ots_info(&["--max-depth", "10", path]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("recursion limit reached"));
+
+    let output = ots_info(&["--max-size", "50", path]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("size limit of 50 bytes"));
+
+    let output = ots_info(&["--max-depth", "lots", path]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn max_lines() {
+    let file = TempPath::with_contents("long.ots", &deep_timestamp(1000));
+    let path = file.0.to_str().unwrap();
+
+    let output = ots_info(&["--max-depth", "2000", path]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().count() <= 1003);
+    assert!(stdout.contains("(truncated, 502 more steps)"));
+
+    let output = ots_info(&["--max-depth", "2000", "--max-lines", "10", path]);
+    assert!(String::from_utf8_lossy(&output.stdout).lines().count() <= 13);
+
+    let output = ots_info(&["--max-depth", "2000", "--full", path]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("truncated"));
+    assert!(stdout.contains("Bitcoin block 100"));
+}
+
+#[test]
+fn verbose() {
+    let file = TempPath::with_contents("verbose.ots", &deep_timestamp(3));
+    let path = file.0.to_str().unwrap();
+
+    let quiet = ots_info(&[path]);
+    assert!(quiet.status.success());
+    assert!(quiet.stderr.is_empty());
+
+    let verbose = ots_info(&["-vv", path]);
+    assert!(verbose.status.success());
+    assert_eq!(verbose.stdout, quiet.stdout);
+    assert!(String::from_utf8_lossy(&verbose.stderr).contains("Magic ok."));
+}
+
+#[test]
+fn prune() {
+    let file = TempPath::with_contents("prune.ots", LARGE_TEST);
+    let path = file.0.to_str().unwrap();
+
+    let output = ots(&["prune", path]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Removed 2 branches"));
+
+    let original = DetachedTimestampFile::from_reader(LARGE_TEST).unwrap();
+    let pruned = DetachedTimestampFile::from_reader(&fs::read(path).unwrap()[..]).unwrap();
+    assert_eq!(pruned.timestamp.anchors(), original.timestamp.anchors());
+    assert!(pruned.serialized_len() < LARGE_TEST.len());
+
+    // Nothing left to prune
+    let output = ots(&["prune", path]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Removed 0 branches"));
+
+    // Pruning a pending-only timestamp would lose the only proof
+    let pending = DetachedTimestampFile {
+        digest_type: DigestType::Sha256,
+        timestamp: Timestamp {
+            start_digest: vec![0x5a; 32],
+            first_step: Step::attestation(Attestation::Pending { uri: "https://example.com".to_owned() }, vec![0x5a; 32])
+        }
+    };
+    let file = TempPath::with_contents("pending.ots", &pending.to_bytes().unwrap());
+    let output = ots(&["prune", file.0.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("no blockchain attestation"));
+    assert_eq!(fs::read(&file.0).unwrap(), pending.to_bytes().unwrap());
+
+    let output = ots(&["prune"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn hex_argument() {
+    let bytes = SMALL_TEST;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let file = TempPath::with_contents("hex.ots", bytes);
+
+    let from_hex = ots_info(&["--hex", &hex]);
+    assert!(from_hex.status.success());
+    let from_file = ots_info(&[file.0.to_str().unwrap()]);
+    assert_eq!(from_hex.stdout, from_file.stdout);
+    assert!(String::from_utf8_lossy(&from_hex.stdout).contains("alice.btc.calendar.opentimestamps.org"));
+
+    let output = ots_info(&["--hex", "0x004f70"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Failed to parse"));
+
+    let output = ots_info(&["--hex", "zz"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Failed to decode"));
+
+    // A file and a hex string at once is an error
+    let output = ots_info(&["--hex", &hex, file.0.to_str().unwrap()]);
+    assert!(!output.status.success());
+}
+
+#[cfg(feature = "calendar")]
+#[test]
+fn upgrade() {
+    use ots::hex;
+
+    // Alice's calendar has the commitment in Bitcoin block 100; Bob's is
+    // behind a broken proxy
+    let alice = mock_http::mock_server(1, |path| {
+        let commitment = hex::from_hex(path.trim_start_matches("/timestamp/")).unwrap();
+        let mut continuation = Timestamp::new(commitment);
+        continuation.attach_path(&[Op::Sha256], Attestation::bitcoin(100)).unwrap();
+        let mut body = vec![];
+        continuation.to_writer(&mut body).unwrap();
+        mock_http::response("200 OK", "application/octet-stream", &body)
+    });
+    let bad_gateway = |_: &str| mock_http::response("502 Bad Gateway", "text/html", b"<html><body>502 Bad Gateway</body></html>");
+    let bob = mock_http::mock_server(2, bad_gateway);
+
+    let mut pending = DetachedTimestampFile::from_timestamp(DigestType::Sha256, Timestamp::new(vec![0x5a; 32])).unwrap();
+    pending.attach_path(vec![Op::Append(vec![1; 16]), Op::Sha256], Attestation::pending(alice.clone()).unwrap()).unwrap();
+    pending.attach_path(vec![Op::Append(vec![2; 16]), Op::Sha256], Attestation::pending(bob.clone()).unwrap()).unwrap();
+    let original = pending.to_bytes().unwrap();
+
+    // Neither calendar is contacted without being allowed
+    let file = TempPath::with_contents("upgrade.ots", &original);
+    let path = file.0.to_str().unwrap();
+    let output = ots(&["upgrade", path]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("not an allowed calendar"));
+    assert_eq!(fs::read(path).unwrap(), original);
+
+    // With only Bob's calendar to try, nothing is upgraded or written
+    let output = ots(&["upgrade", "--calendar", &bob, path]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Failed to upgrade from {}", bob)));
+    assert!(stdout.contains("left"));
+    assert_eq!(fs::read(path).unwrap(), original);
+
+    // With both, Alice's upgrade is kept despite Bob's failure
+    let output = ots(&["upgrade", "--calendar", &alice, "--calendar", &bob, path]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Upgraded from {}", alice)));
+    assert!(stdout.contains(&format!("Failed to upgrade from {}", bob)));
+    let upgraded = DetachedTimestampFile::from_reader(&fs::read(path).unwrap()[..]).unwrap();
+    assert_eq!(upgraded.timestamp.first_bitcoin_height().map(u32::from), Some(100));
+    assert!(upgraded.timestamp.commitment_for(&bob).is_some());
+}
@@ -0,0 +1,53 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # Mock HTTP server
+//!
+//! A minimal HTTP server for tests, shared by the unit tests of the library
+//! and the command-line tests, which include this file by path
+//!
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// Serves `count` requests on a local port, answering each with what
+/// `respond` gives for its path, and returns the base URL
+pub fn mock_server<F: Fn(&str) -> Vec<u8> + Send + 'static>(count: usize, respond: F) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming().take(count) {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            let path = request_line.split(' ').nth(1).unwrap_or("");
+            stream.write_all(&respond(path)).unwrap();
+        }
+    });
+    base_url
+}
+
+/// Builds a complete response with the given status line, such as
+/// `200 OK`, content type and body
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    ).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
@@ -0,0 +1,57 @@
+// Copyright (C) The OpenTimestamps developers
+//
+// This file is part of rust-opentimestamps.
+//
+// It is subject to the license terms in the LICENSE file found in the
+// top-level directory of this distribution.
+//
+// No part of rust-opentimestamps including this file, may be copied, modified,
+// propagated, or distributed except according to the terms contained in the
+// LICENSE file.
+
+//! # Temporary paths
+//!
+//! Paths for tests to write to, shared by the unit tests of the library and
+//! the command-line tests, which include this file by path
+//!
+
+// Each test crate including this uses only part of it
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+/// A path in the temporary directory, unique to the test process, which is
+/// deleted when dropped along with anything written there. Cleanup happens
+/// even if the test panics.
+pub struct TempPath(pub PathBuf);
+
+impl TempPath {
+    /// A path for `name`, which must be unique among the tests of a crate
+    pub fn new(name: &str) -> TempPath {
+        TempPath(env::temp_dir().join(format!("ots-test-{}-{}", process::id(), name)))
+    }
+
+    /// A path for `name`, written with `contents`
+    pub fn with_contents(name: &str, contents: &[u8]) -> TempPath {
+        let path = TempPath::new(name);
+        fs::write(&path.0, contents).unwrap();
+        path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}